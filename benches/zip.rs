@@ -0,0 +1,101 @@
+use std::{
+  collections::HashSet,
+  env, fs,
+  io::{Cursor, Write},
+  path::{Path, PathBuf},
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use push_fn_lib::zip::{create_zip, ZipOptions};
+use rand::{distributions::Alphanumeric, Rng};
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+const NUM_FILES: usize = 2_000;
+const FILE_SIZE: usize = 16 * 1024;
+
+/// Writes a tree shaped a little like node_modules: lots of small, fairly compressible files
+fn fixture() -> (PathBuf, HashSet<String>) {
+  let dir = env::temp_dir().join(format!("push-fns-bench-{}", std::process::id()));
+  let mut rng = rand::thread_rng();
+  let files = (0..NUM_FILES)
+    .map(|ix| {
+      let package = dir.join(format!("package-{}", ix / 20));
+      fs::create_dir_all(&package).unwrap();
+      let words: String = (0..FILE_SIZE / 8)
+        .map(|_| {
+          let len = rng.gen_range(2..8);
+          let word: String = (&mut rng)
+            .sample_iter(&Alphanumeric)
+            .take(len)
+            .map(char::from)
+            .collect();
+          format!("{} ", word)
+        })
+        .collect();
+      let path = package.join(format!("file-{}.js", ix));
+      fs::write(&path, words).unwrap();
+      path.to_str().unwrap().to_string()
+    })
+    .collect();
+  (dir, files)
+}
+
+/// The archive as it was built before entries were compressed in parallel: each file is read and
+/// compressed straight into the one writer, one after another, in the same order
+fn serial_zip(base: &Path, files: HashSet<String>) -> Vec<u8> {
+  let mut names: Vec<(String, String)> = files
+    .into_iter()
+    .map(|file| {
+      let name = Path::new(&file).strip_prefix(base).unwrap();
+      (name.to_str().unwrap().to_string(), file)
+    })
+    .collect();
+  names.sort();
+  let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+  for (name, file) in names {
+    let contents = fs::read(&file).unwrap();
+    zip.start_file(name, SimpleFileOptions::default()).unwrap();
+    zip.write_all(&contents).unwrap();
+  }
+  zip.finish().unwrap().into_inner()
+}
+
+/// Compares the previous serial build with the parallel one on a single worker (which shows what
+/// compressing each entry on its own costs) and on every available core
+fn bench_create_zip(c: &mut Criterion) {
+  let (dir, files) = fixture();
+  let path = dir.to_str().unwrap().to_string();
+  let options = ZipOptions::default();
+  let mut group = c.benchmark_group("create_zip");
+  group.sample_size(10);
+  let single = rayon::ThreadPoolBuilder::new()
+    .num_threads(1)
+    .build()
+    .unwrap();
+  group.bench_function("serial baseline", |b| {
+    b.iter_batched(
+      || files.clone(),
+      |files| serial_zip(&dir, files),
+      BatchSize::LargeInput,
+    )
+  });
+  group.bench_function("single thread", |b| {
+    b.iter_batched(
+      || files.clone(),
+      |files| single.install(|| create_zip(&path, files, None, &options).unwrap()),
+      BatchSize::LargeInput,
+    )
+  });
+  group.bench_function("all threads", |b| {
+    b.iter_batched(
+      || files.clone(),
+      |files| create_zip(&path, files, None, &options).unwrap(),
+      BatchSize::LargeInput,
+    )
+  });
+  group.finish();
+  fs::remove_dir_all(dir).unwrap();
+}
+
+criterion_group!(benches, bench_create_zip);
+criterion_main!(benches);
@@ -1,4 +1,4 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 /// A simple tool to upload serverless function assets
 #[derive(Parser, Debug)]
@@ -21,19 +21,151 @@ pub enum Commands {
   Gcp(GCPArgs),
 }
 
+/// The lambda runtimes a layer can be built for. Lambda only adds layer contents to the module
+/// path when they live under the runtime's directory (e.g. `nodejs/node_modules`).
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerRuntime {
+  /// Place layer files under `nodejs/`
+  Nodejs,
+  /// Place layer files under `python/`
+  Python,
+  /// Place layer files under `ruby/`
+  Ruby,
+  /// Place layer files under `java/lib/`
+  Java,
+  /// Place layer files under the directory given by `--layer-prefix`
+  Custom,
+}
+
+/// How symlinks found in the source tree are put into the archives
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkMode {
+  /// Store the file the symlink points at (symlinks to directories are searched through)
+  #[default]
+  Follow,
+  /// Store the symlink itself, pointing at the same target
+  Preserve,
+  /// Fail if any matched path is or goes through a symlink
+  Reject,
+}
+
+/// How the entries of an archive are compressed
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+  /// No compression at all
+  Stored,
+  /// Deflate, which every unzip tool can read
+  #[default]
+  Deflate,
+  /// Zstandard, which is fast and compresses well but which many unzip tools can't read
+  Zstd,
+  /// Bzip2, which compresses well but slowly, and which some unzip tools can't read
+  Bzip2,
+}
+
+/// The globs of already compressed files that are stored as they are unless `--store` says
+/// otherwise
+pub const STORED_BY_DEFAULT: [&str; 16] = [
+  "**/*.png",
+  "**/*.jpg",
+  "**/*.jpeg",
+  "**/*.gif",
+  "**/*.webp",
+  "**/*.avif",
+  "**/*.woff",
+  "**/*.woff2",
+  "**/*.gz",
+  "**/*.tgz",
+  "**/*.br",
+  "**/*.bz2",
+  "**/*.xz",
+  "**/*.zst",
+  "**/*.zip",
+  "**/*.jar",
+];
+
+/// The arguments deciding how files are put into the archives, shared by every provider
+#[derive(Args, Debug, Clone)]
+pub struct ArchiveArgs {
+  /// Stamp entries with the current time and skip sorting them, instead of building a
+  /// reproducible zip (which honours SOURCE_DATE_EPOCH)
+  #[arg(long = "no-reproducible", action = clap::ArgAction::SetFalse)]
+  pub reproducible: bool,
+
+  /// How symlinks found in the source tree are treated
+  #[arg(long, value_enum, default_value_t = SymlinkMode::Follow)]
+  pub symlinks: SymlinkMode,
+
+  /// Also skip files matched by the .gitignore, .npmignore and .pushfnsignore files under the
+  /// input path, and by a .pushfnsignore in the working directory. A node_modules directory an
+  /// include glob names, like node_modules/**, is archived even if it's ignored
+  #[arg(long, default_value_t = false)]
+  pub ignore_files: bool,
+
+  /// Match the include, exclude, exec and store globs regardless of case
+  #[arg(long, default_value_t = false)]
+  pub ignore_case: bool,
+
+  /// A glob of files (relative to the input path) to make executable in the archives, whatever
+  /// their permissions on disk, e.g. `bootstrap`. Can be given more than once.
+  #[arg(long, value_name = "GLOB")]
+  pub exec: Vec<String>,
+
+  /// How the archive entries are compressed
+  #[arg(long, value_enum, default_value_t = Compression::Deflate)]
+  pub compression: Compression,
+
+  /// The compression level, 1-9 for deflate and bzip2 or 1-22 for zstd (the method's own
+  /// default when left out)
+  #[arg(long)]
+  pub compression_level: Option<i32>,
+
+  /// A glob of files that are already compressed, which are stored as they are rather than
+  /// compressed again. Giving any replaces the defaults; pass `--store ''` to compress everything.
+  #[arg(long, value_name = "GLOB", default_values = STORED_BY_DEFAULT)]
+  pub store: Vec<String>,
+
+  /// Build the archives in temporary files instead of in memory, keeping memory use bounded for
+  /// very large archives
+  #[arg(long, default_value_t = false)]
+  pub spool: bool,
+}
+
+impl Default for ArchiveArgs {
+  /// The same options the command line defaults to
+  fn default() -> Self {
+    ArchiveArgs {
+      reproducible: true,
+      symlinks: SymlinkMode::Follow,
+      ignore_files: false,
+      ignore_case: false,
+      exec: Vec::new(),
+      compression: Compression::Deflate,
+      compression_level: None,
+      store: STORED_BY_DEFAULT
+        .iter()
+        .map(|glob| glob.to_string())
+        .collect(),
+      spool: false,
+    }
+  }
+}
+
 /// The arguments for the GCP upload function
 #[derive(Args, Debug)]
 pub struct GCPArgs {
-  /// An array of globs defining what to bundle
+  /// An array of globs defining what to bundle. A glob starting with `!` leaves out files an
+  /// earlier one matched
   #[arg(short, long, default_values_t = [String::from("**")])]
   pub include: Vec<String>,
 
-  /// An array of globs defining what not to bundle
+  /// An array of globs defining what not to bundle. A glob starting with `!` keeps files an
+  /// earlier one excluded, and excluding a directory excludes everything in it
   #[arg(short, long)]
   pub exclude: Vec<String>,
 
   /// A list of buckets to upload to (same order as the regions please)
-  #[arg(short, long, required = true)]
+  #[arg(short, long, required_unless_present = "output_dir")]
   pub buckets: Vec<String>,
 
   /// The path/filename of the zip file in the bucket (you don't need to add the .zip extension)
@@ -48,30 +180,84 @@ pub struct GCPArgs {
   #[arg(short, long)]
   pub version_suffix: Option<String>,
 
-  /// An optional path within the zip to save the files to
+  /// An optional path within the zip to save the files to (must be relative)
   #[arg(long)]
   pub root_dir: Option<String>,
+
+  /// How the files are put into the archives
+  #[command(flatten)]
+  pub archive: ArchiveArgs,
+
+  /// The maximum number of uploads to run at once
+  #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+  pub concurrency: u16,
+
+  /// Upload the archives even if the remote objects already have identical content
+  #[arg(long, default_value_t = false)]
+  pub force: bool,
+
+  /// Print the files going into each archive, the archive sizes and every destination, then exit
+  /// without uploading anything
+  #[arg(long, default_value_t = false)]
+  pub dry_run: bool,
+
+  /// A directory to write the archives to, named the same way as the uploaded objects. Buckets
+  /// become optional, so the archives can be built without any cloud credentials.
+  #[arg(long)]
+  pub output_dir: Option<String>,
+  /// Archives larger than this many MiB are uploaded in a resumable session
+  #[arg(long, default_value_t = 8)]
+  pub resumable_threshold: u64,
+
+  /// The size in MiB of each chunk of a resumable upload
+  #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+  pub chunk_size: u64,
+
+  /// How many times each request is attempted before a transient failure (throttling, a 5xx or a
+  /// timeout) fails the upload
+  #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+  pub retry_attempts: u32,
+
+  /// The delay in milliseconds before the first retry, doubled for each retry after that
+  #[arg(long, default_value_t = 250)]
+  pub retry_base_delay: u64,
+
+  /// The longest delay in milliseconds between two attempts
+  #[arg(long, default_value_t = 20000)]
+  pub retry_max_delay: u64,
+
+  /// Wait exactly the backoff delay between attempts rather than a random delay up to it
+  #[arg(long = "no-retry-jitter", action = clap::ArgAction::SetFalse)]
+  pub retry_jitter: bool,
 }
 
 /// The arguments for the AWS upload function
 #[derive(Args, Debug)]
 pub struct AWSArgs {
-  /// An array of globs defining what to bundle
+  /// An array of globs defining what to bundle. A glob starting with `!` leaves out files an
+  /// earlier one matched
   #[arg(short, long, default_values_t = [String::from("**")])]
   pub include: Vec<String>,
 
-  /// An array of globs defining what not to bundle
+  /// An array of globs defining what not to bundle. A glob starting with `!` keeps files an
+  /// earlier one excluded, and excluding a directory excludes everything in it
   #[arg(short, long)]
   pub exclude: Vec<String>,
 
   /// A list of buckets to upload to (same order as the regions please)
-  #[arg(short, long, required = true)]
+  #[arg(short, long, required_unless_present_any = ["output_dir", "dest"])]
   pub buckets: Vec<String>,
 
-  /// A list of regions to upload the assets to
-  #[arg(short, long, required = true)]
+  /// A list of regions to upload the assets to, one per bucket or a single region for every bucket.
+  /// When no regions are given, each bucket's region is looked up.
+  #[arg(short, long)]
   pub regions: Vec<String>,
 
+  /// A bucket to upload to, and optionally the region it lives in, as `bucket@region`. The region
+  /// is looked up when left out. Can be given more than once, and alongside `--buckets`.
+  #[arg(long, value_name = "BUCKET[@REGION]")]
+  pub dest: Vec<String>,
+
   /// The path/filename of the zip file in the bucket (you don't need to add the .zip extension)
   #[arg(short, long)]
   pub function_key: String,
@@ -88,15 +274,85 @@ pub struct AWSArgs {
   #[arg(long, default_values_t = [String::from("node_modules/**")])]
   pub layer_globs: Vec<String>,
 
+  /// The runtime the layer is for, which decides the directory the layer files are placed in
+  /// (nodejs by default when --symlink-node-modules is set)
+  #[arg(long, value_enum)]
+  pub layer_runtime: Option<LayerRuntime>,
+
+  /// The directory to place the layer files in when the layer runtime is custom
+  #[arg(long, required_if_eq("layer_runtime", "custom"))]
+  pub layer_prefix: Option<String>,
+
+  /// Only archive the node_modules packages needed in production, working them out from the
+  /// package.json and the package-lock.json or yarn.lock in the input path. pnpm projects (those
+  /// with just a pnpm-lock.yaml) and the packages of an npm, yarn or pnpm workspace have the
+  /// package.json dependencies followed through the installed node_modules instead, copying the
+  /// packages in from wherever they're installed
+  #[arg(long, default_value_t = false)]
+  pub prune_node_modules: bool,
+
+  /// Leave docs, type declarations, tests and CI config out of node_modules
+  #[arg(long, default_value_t = false)]
+  pub prune_junk: bool,
+
   /// An optional string to append to layer and function keys to use as a version indicator
   #[arg(short, long)]
   pub version_suffix: Option<String>,
 
-  /// An optional path within the zip to save the files to
+  /// An optional path within the function zip to save the files to (must be relative)
   #[arg(long)]
   pub root_dir: Option<String>,
 
+  /// How the files are put into the archives
+  #[command(flatten)]
+  pub archive: ArchiveArgs,
+
   /// Should we create a symlink from the function directory to the layer node_modules?
   #[arg(short, long, default_value_t = false)]
   pub symlink_node_modules: bool,
+  /// The maximum number of uploads to run at once
+  #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+  pub concurrency: u16,
+
+  /// Upload the archives even if the remote objects already have identical content
+  #[arg(long, default_value_t = false)]
+  pub force: bool,
+
+  /// Print the files going into each archive, the archive sizes and every destination, then exit
+  /// without uploading anything
+  #[arg(long, default_value_t = false)]
+  pub dry_run: bool,
+
+  /// A directory to write the archives to, named the same way as the uploaded objects. Buckets
+  /// become optional, so the archives can be built without any cloud credentials.
+  #[arg(long)]
+  pub output_dir: Option<String>,
+  /// Archives larger than this many MiB are uploaded to S3 in parts
+  #[arg(long, default_value_t = 16)]
+  pub multipart_threshold: u64,
+
+  /// The size in MiB of each part of a multipart upload
+  #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u64).range(5..=5120))]
+  pub part_size: u64,
+
+  /// The maximum number of parts of each multipart upload to send at once
+  #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+  pub part_concurrency: u16,
+
+  /// How many times each request is attempted before a transient failure (throttling, a 5xx or a
+  /// timeout) fails the upload
+  #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+  pub retry_attempts: u32,
+
+  /// The delay in milliseconds before the first retry, doubled for each retry after that
+  #[arg(long, default_value_t = 250)]
+  pub retry_base_delay: u64,
+
+  /// The longest delay in milliseconds between two attempts
+  #[arg(long, default_value_t = 20000)]
+  pub retry_max_delay: u64,
+
+  /// Wait exactly the backoff delay between attempts rather than a random delay up to it
+  #[arg(long = "no-retry-jitter", action = clap::ArgAction::SetFalse)]
+  pub retry_jitter: bool,
 }
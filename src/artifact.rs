@@ -0,0 +1,653 @@
+use std::{
+  env, fmt,
+  fs::{self, File},
+  io::{self, Cursor, Read, Seek, SeekFrom, Write},
+  ops::Range,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use bytes::Bytes;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use tempfile::TempPath;
+use zip::ZipArchive;
+
+use crate::{
+  error::{Error, Result},
+  search::{canonical_base, literal_pattern, search},
+  zip::{sources, write_sources, SymLink, ZipOptions, ZipSource},
+};
+
+/// The unix file type bits which mark a zip entry as a symlink
+const S_IFLNK: u32 = 0o120000;
+
+/// One entry of a built archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+  /// The path of the entry within the archive
+  pub name: String,
+  /// The uncompressed size in bytes
+  pub size: u64,
+  /// The compressed size in bytes
+  pub compressed_size: u64,
+  /// Where the entry points, if it is a symlink
+  pub symlink_target: Option<String>,
+}
+
+/// Where the zipped contents of an artifact are kept
+#[derive(Debug, Clone)]
+pub enum ArtifactData {
+  /// The whole archive, held in memory
+  Memory(Bytes),
+  /// An archive spooled to a temporary file, which is deleted once the last clone is dropped
+  Spooled {
+    /// The temporary file
+    path: Arc<TempPath>,
+    /// The size of the archive in bytes
+    size: u64,
+  },
+}
+
+impl ArtifactData {
+  /// The size of the archive in bytes
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use push_fn_lib::artifact::ArtifactData;
+  ///
+  /// assert_eq!(ArtifactData::Memory(b"zip".to_vec().into()).size(), 3);
+  /// ```
+  pub fn size(&self) -> u64 {
+    match self {
+      ArtifactData::Memory(data) => data.len() as u64,
+      ArtifactData::Spooled { size, .. } => *size,
+    }
+  }
+
+  /// Reads the bytes in `range`, so that a spooled archive is only ever read a part at a time
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use push_fn_lib::artifact::ArtifactData;
+  ///
+  /// let data = ArtifactData::Memory(b"zip".to_vec().into());
+  /// assert_eq!(&data.read_range(1..3).unwrap()[..], b"ip");
+  /// ```
+  pub fn read_range(&self, range: Range<u64>) -> Result<Bytes> {
+    match self {
+      ArtifactData::Memory(data) => Ok(data.slice(range.start as usize..range.end as usize)),
+      ArtifactData::Spooled { path, .. } => {
+        let path: &Path = path;
+        let mut file = File::open(path).map_err(|e| Error::io(path, e))?;
+        let mut buffer = vec![0; (range.end - range.start) as usize];
+        file
+          .seek(SeekFrom::Start(range.start))
+          .and_then(|_| file.read_exact(&mut buffer))
+          .map_err(|e| Error::io(path, e))?;
+        Ok(Bytes::from(buffer))
+      }
+    }
+  }
+
+  /// Opens the whole archive for reading from the start
+  pub(crate) fn reader(&self) -> Result<ArtifactReader> {
+    match self {
+      ArtifactData::Memory(data) => Ok(ArtifactReader::Memory(Cursor::new(data.clone()))),
+      ArtifactData::Spooled { path, .. } => {
+        let path: &Path = path;
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        Ok(ArtifactReader::Spooled(file))
+      }
+    }
+  }
+}
+
+/// Reads an archive wherever it is kept
+pub(crate) enum ArtifactReader {
+  Memory(Cursor<Bytes>),
+  Spooled(File),
+}
+
+impl Read for ArtifactReader {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    match self {
+      ArtifactReader::Memory(cursor) => cursor.read(buf),
+      ArtifactReader::Spooled(file) => file.read(buf),
+    }
+  }
+}
+
+impl Seek for ArtifactReader {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    match self {
+      ArtifactReader::Memory(cursor) => cursor.seek(pos),
+      ArtifactReader::Spooled(file) => file.seek(pos),
+    }
+  }
+}
+
+/// A zip archive that has been built once and can be uploaded to any number of destinations
+#[derive(Debug, Clone)]
+pub struct Artifact {
+  /// The object key the archive is uploaded to
+  pub key: String,
+  /// The zipped contents
+  pub data: ArtifactData,
+  /// The hex encoded SHA-256 of the zipped contents
+  pub sha256: String,
+  /// The hex encoded MD5 of the zipped contents, which is what Google Cloud Storage reports for
+  /// an object
+  pub md5: String,
+}
+
+/// Hashes whatever is written to it with every hash an [`Artifact`] keeps, so that the archive is
+/// only read once
+#[derive(Default)]
+struct ContentHasher {
+  sha256: Sha256,
+  md5: Md5,
+}
+
+impl Write for ContentHasher {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.sha256.update(buf);
+    self.md5.update(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl ContentHasher {
+  /// The hex encoded SHA-256 and MD5
+  fn finish(self) -> (String, String) {
+    (
+      hex::encode(self.sha256.finalize()),
+      hex::encode(self.md5.finalize()),
+    )
+  }
+}
+
+impl Artifact {
+  /// Wraps an already zipped buffer, computing its content hashes
+  pub fn new(key: String, data: Vec<u8>) -> Self {
+    let mut hasher = ContentHasher::default();
+    hasher.sha256.update(&data);
+    hasher.md5.update(&data);
+    let (sha256, md5) = hasher.finish();
+    Artifact {
+      key,
+      data: ArtifactData::Memory(Bytes::from(data)),
+      sha256,
+      md5,
+    }
+  }
+
+  /// Wraps an archive that has been spooled to a temporary file, computing its content hashes by
+  /// streaming the file rather than loading it
+  pub fn spooled(key: String, path: TempPath) -> Result<Self> {
+    let mut file = File::open(&path).map_err(|e| Error::io(&*path, e))?;
+    let mut hasher = ContentHasher::default();
+    let size = io::copy(&mut file, &mut hasher).map_err(|e| Error::io(&*path, e))?;
+    let (sha256, md5) = hasher.finish();
+    Ok(Artifact {
+      key,
+      data: ArtifactData::Spooled {
+        path: Arc::new(path),
+        size,
+      },
+      sha256,
+      md5,
+    })
+  }
+
+  /// Searches `input_path` and zips up the matching files, along with any `extra` files from
+  /// elsewhere, failing if there's nothing to archive
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use push_fn_lib::{artifact::Artifact, zip::ZipOptions};
+  ///
+  /// let artifact = Artifact::build(
+  ///   "my-function.zip".to_string(),
+  ///   &".".to_string(),
+  ///   &["src/artifact.rs".to_string()],
+  ///   &[],
+  ///   &[],
+  ///   None,
+  ///   &ZipOptions::default(),
+  /// )
+  /// .unwrap();
+  /// assert_eq!(artifact.sha256.len(), 64);
+  /// ```
+  pub fn build(
+    key: String,
+    input_path: &String,
+    include: &[String],
+    exclude: &[String],
+    extra: &[ZipSource],
+    symlink: Option<SymLink>,
+    options: &ZipOptions,
+  ) -> Result<Self> {
+    let file_list = search(
+      input_path,
+      include,
+      exclude,
+      options.symlinks,
+      options.ignore_files,
+      options.ignore_case,
+    )?;
+    let mut sources = sources(input_path, file_list)?;
+    sources.extend_from_slice(extra);
+    if sources.is_empty() {
+      return Err(Error::NoFilesMatched {
+        path: input_path.clone(),
+      });
+    }
+    Artifact::from_sources(key, &sources, symlink, options)
+  }
+
+  /// Zips up files from anywhere on disk, each placed where it's named in the archive. The archive
+  /// is built in memory, or spooled to a temporary file if the options ask for it.
+  pub fn from_sources(
+    key: String,
+    sources: &[ZipSource],
+    symlink: Option<SymLink>,
+    options: &ZipOptions,
+  ) -> Result<Self> {
+    if !options.spool {
+      let archive = write_sources(sources, symlink, options, Cursor::new(Vec::new()))?;
+      return Ok(Artifact::new(key, archive.into_inner()));
+    }
+    let file = tempfile::Builder::new()
+      .prefix("push-fns-")
+      .suffix(".zip")
+      .tempfile()
+      .map_err(|e| Error::io(env::temp_dir(), e))?;
+    let file = write_sources(sources, symlink, options, file)?;
+    Artifact::spooled(key, file.into_temp_path())
+  }
+
+  /// Where the archive is written to within `dir`, named the same way as the uploaded object
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use push_fn_lib::artifact::Artifact;
+  /// use std::path::{Path, PathBuf};
+  ///
+  /// let artifact = Artifact::new("fns/my-fn-1.0.0.zip".to_string(), Vec::new());
+  /// assert_eq!(
+  ///   artifact.local_path(Path::new("dist")),
+  ///   PathBuf::from("dist/fns/my-fn-1.0.0.zip")
+  /// );
+  /// ```
+  pub fn local_path(&self, dir: &Path) -> PathBuf {
+    dir.join(&self.key)
+  }
+
+  /// Writes the archive into `dir`, creating any directories its key needs, and returns where it
+  /// was written
+  pub fn write_to(&self, dir: &Path) -> Result<PathBuf> {
+    let path = self.local_path(dir);
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+    }
+    let mut reader = self.data.reader()?;
+    let mut file = File::create(&path).map_err(|e| Error::io(&path, e))?;
+    io::copy(&mut reader, &mut file).map_err(|e| Error::io(&path, e))?;
+    Ok(path)
+  }
+
+  /// Lists the entries of the archive in the order they were written
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use push_fn_lib::{artifact::Artifact, zip::ZipOptions};
+  ///
+  /// let artifact = Artifact::build(
+  ///   "my-function.zip".to_string(),
+  ///   &".".to_string(),
+  ///   &["src/artifact.rs".to_string()],
+  ///   &[],
+  ///   &[],
+  ///   None,
+  ///   &ZipOptions::default(),
+  /// )
+  /// .unwrap();
+  /// let entries = artifact.entries().unwrap();
+  /// assert_eq!(entries[0].name, "src/artifact.rs");
+  /// ```
+  pub fn entries(&self) -> Result<Vec<ArchiveEntry>> {
+    let mut archive = ZipArchive::new(self.data.reader()?)?;
+    (0..archive.len())
+      .map(|ix| {
+        let mut file = archive.by_index(ix)?;
+        let is_symlink = file
+          .unix_mode()
+          .is_some_and(|mode| mode & 0o170000 == S_IFLNK);
+        let symlink_target = match is_symlink {
+          true => {
+            let mut target = String::new();
+            file
+              .read_to_string(&mut target)
+              .map_err(|e| Error::io(file.name(), e))?;
+            Some(target)
+          }
+          false => None,
+        };
+        Ok(ArchiveEntry {
+          name: file.name().to_string(),
+          size: file.size(),
+          compressed_size: file.compressed_size(),
+          symlink_target,
+        })
+      })
+      .collect()
+  }
+
+  /// Describes what pushing this archive would do: every entry with its sizes, the archive totals
+  /// and each destination it would be uploaded to
+  pub fn plan(&self, destinations: &[String]) -> Result<String> {
+    let entries = self.entries()?;
+    let mut lines = vec![self.to_string()];
+    lines.extend(entries.iter().map(|entry| match &entry.symlink_target {
+      Some(target) => format!("  {} -> {} (symlink)", entry.name, target),
+      None => format!(
+        "  {} ({} bytes, {} compressed)",
+        entry.name, entry.size, entry.compressed_size
+      ),
+    }));
+    lines.push(format!(
+      "  {} entries, {} bytes uncompressed, {} bytes compressed",
+      entries.len(),
+      entries.iter().map(|entry| entry.size).sum::<u64>(),
+      self.data.size()
+    ));
+    lines.extend(
+      destinations
+        .iter()
+        .map(|destination| format!("  -> {}", destination)),
+    );
+    Ok(lines.join("\n"))
+  }
+}
+
+impl fmt::Display for Artifact {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} ({} bytes) sha256:{}",
+      self.key,
+      self.data.size(),
+      self.sha256
+    )
+  }
+}
+
+/// Exclude patterns that keep the archives written to `output_dir` out of the ones built from
+/// `input_path`. An output directory inside the input path is excluded as a whole, and when it's the
+/// input path itself just the archives named by `keys` are. The directory doesn't have to exist yet.
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::artifact::output_excludes;
+///
+/// assert_eq!(output_excludes(".", "dist", &["my-fn.zip"]).unwrap(), ["dist"]);
+/// assert_eq!(output_excludes(".", ".", &["my-fn.zip"]).unwrap(), ["my-fn.zip"]);
+/// ```
+pub fn output_excludes(input_path: &str, output_dir: &str, keys: &[&str]) -> Result<Vec<String>> {
+  let base = canonical_base(input_path)?;
+  let dir = env::current_dir()
+    .map_err(|e| Error::io(".", e))?
+    .join(output_dir);
+  let Ok(relative) = resolve_existing(&dir)
+    .strip_prefix(&base)
+    .map(Path::to_path_buf)
+  else {
+    return Ok(Vec::new());
+  };
+  Ok(match relative.as_os_str().is_empty() {
+    true => keys
+      .iter()
+      .map(|key| literal_pattern(Path::new(key)))
+      .collect(),
+    false => vec![literal_pattern(&relative)],
+  })
+}
+
+/// `path` with its longest existing ancestor canonicalised, leaving the directories below that as
+/// they are
+fn resolve_existing(path: &Path) -> PathBuf {
+  let mut existing = path;
+  let mut missing = Vec::new();
+  loop {
+    if let Ok(resolved) = fs::canonicalize(existing) {
+      return missing
+        .iter()
+        .rev()
+        .fold(resolved, |path, name| path.join(name));
+    }
+    match (existing.parent(), existing.file_name()) {
+      (Some(parent), Some(name)) => {
+        missing.push(name);
+        existing = parent;
+      }
+      _ => return path.to_path_buf(),
+    }
+  }
+}
+
+/// Builds the object key for an archive, appending the version suffix if there is one
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::artifact::object_key;
+///
+/// assert_eq!(object_key("my-fn", &Some("1.0.0".to_string())), "my-fn-1.0.0.zip");
+/// assert_eq!(object_key("my-fn", &None), "my-fn.zip");
+/// ```
+pub fn object_key(key: &str, version_suffix: &Option<String>) -> String {
+  match version_suffix {
+    Some(version) => format!("{}-{}.zip", key, version),
+    None => format!("{}.zip", key),
+  }
+}
+
+/// The characters, besides ASCII letters and digits, that are safe in both S3 and Cloud Storage
+/// object names
+const SAFE_KEY_PUNCTUATION: &str = "!-_.'()/";
+
+/// Checks that part of an object key (`name` says which, for the error message) is non-empty and
+/// only uses characters that are safe in both S3 and Cloud Storage, without empty, `.` or `..`
+/// path segments
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::artifact::validate_key;
+///
+/// assert!(validate_key("function key", "fns/my-fn").is_ok());
+/// assert!(validate_key("function key", "").is_err());
+/// assert!(validate_key("function key", "my fn").is_err());
+/// assert!(validate_key("function key", "../my-fn").is_err());
+/// ```
+pub fn validate_key(name: &str, value: &str) -> Result<()> {
+  if value.is_empty() {
+    return Err(Error::InvalidArgs(format!(
+      "the {} must not be empty",
+      name
+    )));
+  }
+  if let Some(c) = value
+    .chars()
+    .find(|c| !c.is_ascii_alphanumeric() && !SAFE_KEY_PUNCTUATION.contains(*c))
+  {
+    return Err(Error::InvalidArgs(format!(
+      "the {} {:?} contains {:?}, which isn't safe in object names (use letters, digits and {})",
+      name, value, c, SAFE_KEY_PUNCTUATION
+    )));
+  }
+  if value
+    .split('/')
+    .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+  {
+    return Err(Error::InvalidArgs(format!(
+      "the {} {:?} must not have empty, `.` or `..` path segments",
+      name, value
+    )));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::temp_dir;
+
+  #[test]
+  fn identical_contents_share_a_hash() {
+    let include = ["src/artifact.rs".to_string()];
+    let first = Artifact::build(
+      "a.zip".to_string(),
+      &".".to_string(),
+      &include,
+      &[],
+      &[],
+      None,
+      &ZipOptions::default(),
+    )
+    .unwrap();
+    let copy = Artifact::new(
+      "b.zip".to_string(),
+      first
+        .data
+        .read_range(0..first.data.size())
+        .unwrap()
+        .to_vec(),
+    );
+    assert_eq!(first.sha256, copy.sha256);
+    assert_eq!(first.md5, copy.md5);
+    assert_eq!(
+      Artifact::new("c.zip".to_string(), b"abc".to_vec()).md5,
+      "900150983cd24fb0d6963f7d28e17f72"
+    );
+  }
+
+  #[test]
+  fn spooled_archives_match_in_memory_ones() {
+    let build = |spool| {
+      Artifact::build(
+        "a.zip".to_string(),
+        &".".to_string(),
+        &["src/**/*.rs".to_string()],
+        &[],
+        &[],
+        None,
+        &ZipOptions {
+          spool,
+          ..ZipOptions::default()
+        },
+      )
+      .unwrap()
+    };
+    let memory = build(false);
+    let spooled = build(true);
+    let ArtifactData::Spooled { path, size } = &spooled.data else {
+      panic!("expected a spooled archive");
+    };
+    let path = path.to_path_buf();
+    assert_eq!(*size, memory.data.size());
+    assert_eq!(spooled.sha256, memory.sha256);
+    assert_eq!(spooled.md5, memory.md5);
+    assert_eq!(spooled.entries().unwrap(), memory.entries().unwrap());
+    let part = 100..size / 2;
+    assert_eq!(
+      spooled.data.read_range(part.clone()).unwrap(),
+      memory.data.read_range(part).unwrap()
+    );
+    drop(spooled);
+    assert!(!path.exists());
+  }
+
+  #[test]
+  fn plan_lists_entries_symlinks_and_destinations() {
+    let artifact = Artifact::build(
+      "a.zip".to_string(),
+      &".".to_string(),
+      &["src/artifact.rs".to_string()],
+      &[],
+      &[],
+      Some(SymLink {
+        path: "node_modules".to_string(),
+        target: "/opt/nodejs/node_modules".to_string(),
+      }),
+      &ZipOptions::default(),
+    )
+    .unwrap();
+    let entries = artifact.entries().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].symlink_target, None);
+    assert!(entries[0].size > 0);
+    assert_eq!(
+      entries[1].symlink_target.as_deref(),
+      Some("/opt/nodejs/node_modules")
+    );
+
+    let plan = artifact
+      .plan(&["s3://bucket/a.zip (eu-west-2)".to_string()])
+      .unwrap();
+    assert!(plan.contains("  src/artifact.rs ("));
+    assert!(plan.contains("  node_modules -> /opt/nodejs/node_modules (symlink)"));
+    assert!(plan.contains("  -> s3://bucket/a.zip (eu-west-2)"));
+  }
+
+  #[test]
+  fn archives_are_written_under_their_key() {
+    let (_guard, dir) = temp_dir();
+    let artifact = Artifact::new("fns/my-fn.zip".to_string(), b"zip".to_vec());
+    let path = artifact.write_to(&dir).unwrap();
+    assert_eq!(path, dir.join("fns").join("my-fn.zip"));
+    assert_eq!(fs::read(&path).unwrap(), b"zip");
+  }
+
+  #[test]
+  fn output_dirs_inside_the_input_path_are_excluded() {
+    let (_guard, dir) = temp_dir();
+    let input = dir.display().to_string();
+    let excludes = |output_dir: &Path| {
+      output_excludes(
+        &input,
+        &output_dir.display().to_string(),
+        &["fns/my-fn.zip"],
+      )
+      .unwrap()
+    };
+    assert_eq!(excludes(&dir.join("dist/[new]")), ["dist/[[]new[]]"]);
+    assert_eq!(excludes(&dir), ["fns/my-fn.zip"]);
+    assert!(excludes(&env::temp_dir().join("elsewhere")).is_empty());
+  }
+
+  #[test]
+  fn build_fails_when_nothing_matches() {
+    let result = Artifact::build(
+      "a.zip".to_string(),
+      &".".to_string(),
+      &["src/*.nothing".to_string()],
+      &[],
+      &[],
+      None,
+      &ZipOptions::default(),
+    );
+    assert!(matches!(result, Err(Error::NoFilesMatched { .. })));
+  }
+}
@@ -1,22 +1,213 @@
+use std::{
+  path::{Component, Path, PathBuf},
+  time::Duration,
+};
+
+use aws_sdk_s3::Client;
+use futures::future::join_all;
+
+pub use crate::args::{AWSArgs, LayerRuntime};
 use crate::{
-  args::AWSArgs,
-  search::search,
-  upload::aws_s3::s3_upload,
-  zip::{create_zip, SymLink},
+  artifact::{object_key, output_excludes, validate_key, Artifact},
+  error::{Error, Result},
+  node_modules::{package_sources, prune, Pruned, JUNK},
+  search::PatternList,
+  upload::{
+    aws_s3::{bucket_region, s3_clients, s3_upload, S3UploadOptions},
+    finish_uploads,
+    retry::RetryPolicy,
+    run_uploads, UploadReport, MIB,
+  },
+  zip::{validate_root_dir, SymLink, ZipOptions},
 };
 
+/// The directory within the layer zip that the layer files are placed in for the chosen runtime.
+/// The symlink to the layer's node_modules only resolves if the layer is a nodejs one, so that's
+/// the runtime when there's a symlink and no other was chosen.
+fn layer_root_dir(args: &AWSArgs) -> Result<Option<PathBuf>> {
+  let prefix = match args.layer_runtime {
+    None if args.symlink_node_modules => "nodejs",
+    None => return Ok(None),
+    Some(LayerRuntime::Nodejs) => "nodejs",
+    Some(LayerRuntime::Python) => "python",
+    Some(LayerRuntime::Ruby) => "ruby",
+    Some(LayerRuntime::Java) => "java/lib",
+    Some(LayerRuntime::Custom) => args.layer_prefix.as_deref().ok_or_else(|| {
+      Error::InvalidArgs("--layer-prefix is required when the layer runtime is custom".to_string())
+    })?,
+  };
+  validate_root_dir(prefix)
+}
+
+/// The symlink from the function's node_modules to the node_modules lambda extracts the layer to
+fn node_modules_symlink(args: &AWSArgs, layer_root: &Option<PathBuf>) -> Result<SymLink> {
+  if let Some(runtime @ (LayerRuntime::Python | LayerRuntime::Ruby | LayerRuntime::Java)) =
+    args.layer_runtime
+  {
+    return Err(Error::InvalidArgs(format!(
+      "--symlink-node-modules can't be used with a {:?} layer",
+      runtime
+    )));
+  }
+  let layer_dir = match layer_root {
+    Some(root) => root
+      .components()
+      .filter_map(|c| match c {
+        Component::Normal(part) => part.to_str(),
+        _ => None,
+      })
+      .collect::<Vec<&str>>()
+      .join("/"),
+    None => "nodejs".to_string(),
+  };
+  Ok(SymLink {
+    target: format!("/opt/{}/node_modules", layer_dir),
+    path: "node_modules".to_string(),
+  })
+}
+
+/// Whether `region` looks like an AWS region name: two or more lowercase words and a number, e.g.
+/// `eu-west-2`, `us-gov-west-1` or `eusc-de-east-1`
+fn is_region_name(region: &str) -> bool {
+  let parts: Vec<&str> = region.split('-').collect();
+  match parts.as_slice() {
+    [words @ .., number] => {
+      words.len() >= 2
+        && words
+          .iter()
+          .all(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_lowercase()))
+        && !number.is_empty()
+        && number.chars().all(|c| c.is_ascii_digit())
+    }
+    _ => false,
+  }
+}
+
+/// The region whose client looks up which region each bucket lives in
+const LOOKUP_REGION: &str = "us-east-1";
+
+/// A bucket to upload to, and the region it lives in if we know it yet
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Destination {
+  bucket: String,
+  region: Option<String>,
+}
+
+fn validate_region(region: &str) -> Result<()> {
+  match is_region_name(region) {
+    true => Ok(()),
+    false => Err(Error::InvalidArgs(format!(
+      "{:?} isn't a valid AWS region name (expected something like eu-west-2)",
+      region
+    ))),
+  }
+}
+
+/// Parses a `--dest` value, either `bucket` or `bucket@region`
+fn parse_dest(dest: &str) -> Result<Destination> {
+  let (bucket, region) = match dest.split_once('@') {
+    Some((bucket, region)) => {
+      validate_region(region)?;
+      (bucket, Some(region.to_string()))
+    }
+    None => (dest, None),
+  };
+  if bucket.is_empty() {
+    return Err(Error::InvalidArgs(format!(
+      "--dest {:?} is missing the bucket name (expected bucket or bucket@region)",
+      dest
+    )));
+  }
+  Ok(Destination {
+    bucket: bucket.to_string(),
+    region,
+  })
+}
+
+/// Every bucket to upload to. Buckets from `--buckets` are paired with `--regions` in order, unless
+/// there's a single region which is then used for every bucket, or none at all in which case each
+/// bucket's region is looked up. Buckets from `--dest` follow.
+fn destinations(args: &AWSArgs) -> Result<Vec<Destination>> {
+  for region in &args.regions {
+    validate_region(region)?;
+  }
+  let regions: Vec<Option<&String>> = match (args.buckets.len(), args.regions.len()) {
+    (buckets, 0) => vec![None; buckets],
+    (buckets, 1) => vec![Some(&args.regions[0]); buckets],
+    (buckets, regions) if buckets == regions => args.regions.iter().map(Some).collect(),
+    (buckets, regions) => {
+      return Err(Error::InvalidArgs(format!(
+        "got {} buckets but {} regions; pass one region per bucket, in the same order, a single \
+         region for every bucket, or use --dest bucket@region",
+        buckets, regions
+      )))
+    }
+  };
+  let mut destinations: Vec<Destination> = args
+    .buckets
+    .iter()
+    .zip(regions)
+    .map(|(bucket, region)| Destination {
+      bucket: bucket.to_string(),
+      region: region.cloned(),
+    })
+    .collect();
+  for dest in &args.dest {
+    destinations.push(parse_dest(dest)?);
+  }
+  Ok(destinations)
+}
+
+/// Looks up the region of every destination that wasn't given one. A failed lookup is kept as
+/// that bucket's result rather than stopping the others.
+async fn resolve_regions(
+  client: &Client,
+  destinations: Vec<Destination>,
+  retry: &RetryPolicy,
+) -> Vec<(String, Result<String>)> {
+  join_all(destinations.into_iter().map(|dest| async move {
+    let region = match dest.region {
+      Some(region) => Ok(region),
+      None => bucket_region(client, &dest.bucket, retry).await,
+    };
+    (dest.bucket, region)
+  }))
+  .await
+}
+
+/// Checks every key that goes into the object names
+fn validate_keys(args: &AWSArgs) -> Result<()> {
+  validate_key("function key", &args.function_key)?;
+  if let Some(layer_key) = &args.layer_key {
+    validate_key("layer key", layer_key)?;
+  }
+  if let Some(version_suffix) = &args.version_suffix {
+    validate_key("version suffix", version_suffix)?;
+  }
+  Ok(())
+}
+
+/// Describes where an artifact is uploaded to in one bucket
+fn s3_destination(bucket: &str, region: &str, artifact: &Artifact) -> String {
+  format!("s3://{}/{} ({})", bucket, artifact.key, region)
+}
+
 /// Zips up function assets and uploads them to AWS S3 for use in lambda functions.
 /// Optionally creates a file for a layer as well as a file for the function itself.
 ///
 /// Example
 /// ```rust
-/// use push_fn_lib::aws::{push_aws, AWSArgs};
+/// use push_fn_lib::{
+///   aws::{push_aws, AWSArgs},
+///   zip::ArchiveArgs,
+/// };
 ///
 /// async fn do_upload() {
 ///   let version = "1.0.0".to_string();
 ///
 ///   let args = AWSArgs {
 ///     regions: vec!["eu-west-2".to_string()],
+///     dest: vec![],
 ///     buckets: vec!["fn-push-testing".to_string()],
 ///     function_key: "aws-test".to_string(),
 ///     include: vec!["src/aws.rs".to_string()],
@@ -24,55 +215,191 @@ use crate::{
 ///     input_path: ".".to_string(),
 ///     layer_key: None,
 ///     layer_globs: vec![],
+///     layer_runtime: None,
+///     layer_prefix: None,
+///     prune_node_modules: false,
+///     prune_junk: false,
 ///     version_suffix: Some(version),
 ///     root_dir: None,
+///     archive: ArchiveArgs::default(),
+///     concurrency: 4,
+///     force: false,
+///     dry_run: false,
+///     output_dir: None,
+///     multipart_threshold: 16,
+///     part_size: 8,
+///     part_concurrency: 4,
+///     retry_attempts: 4,
+///     retry_base_delay: 250,
+///     retry_max_delay: 20000,
+///     retry_jitter: true,
 ///     symlink_node_modules: false,
 ///   };
-///   push_aws(args).await;
+///   push_aws(args).await.unwrap();
 /// }
 /// ```
-pub async fn push_aws(args: AWSArgs) {
-  let fn_object_key = match args.version_suffix.clone() {
-    Some(version) => format!("{}-{}.zip", args.function_key, version),
-    None => format!("{}.zip", args.function_key),
+pub async fn push_aws(args: AWSArgs) -> Result<()> {
+  let destinations = destinations(&args)?;
+  validate_keys(&args)?;
+  let root_dir = match &args.root_dir {
+    Some(root_dir) => validate_root_dir(root_dir)?,
+    None => None,
   };
-  let mut exclude = args.exclude.clone();
-  if args.layer_key.is_some() {
-    exclude.append(args.layer_globs.clone().as_mut());
-    let layer_object_key = match args.version_suffix {
-      Some(version) => format!("{}-{}.zip", args.layer_key.unwrap(), version),
-      None => format!("{}.zip", args.layer_key.unwrap()),
-    };
-    for (ix, bucket) in args.buckets.iter().enumerate() {
-      let file_list = search(&args.input_path, &args.layer_globs, &[]);
-      let buffer = create_zip(&args.input_path, file_list, None);
-      s3_upload(&args.regions[ix], bucket, &layer_object_key, buffer).await;
+  let fn_zip_options = ZipOptions::new(&args.archive, root_dir)?;
+  let layer_zip_options = ZipOptions {
+    root_dir: layer_root_dir(&args)?,
+    ..fn_zip_options.clone()
+  };
+  let symlink = match args.symlink_node_modules {
+    true => Some(node_modules_symlink(&args, &layer_zip_options.root_dir)?),
+    false => None,
+  };
+  // node_modules are pruned in whichever archive they end up in
+  let Pruned { patterns, packages } = match args.prune_node_modules {
+    true => prune(Path::new(&args.input_path))?,
+    false => Pruned::default(),
+  };
+  let junk: Vec<String> = match args.prune_junk {
+    true => JUNK.iter().map(|glob| glob.to_string()).collect(),
+    false => Vec::new(),
+  };
+  let junk_patterns = PatternList::new(&junk, args.archive.ignore_case)?;
+  let mut packages = package_sources(&packages, args.archive.symlinks)?;
+  packages.retain(|source| junk_patterns.matched_path_or_any_parents(&source.name) != Some(true));
+  let function_key = object_key(&args.function_key, &args.version_suffix);
+  let layer_key = args
+    .layer_key
+    .as_ref()
+    .map(|layer_key| object_key(layer_key, &args.version_suffix));
+  // archives written by an earlier run aren't packed into this one
+  let outputs = match &args.output_dir {
+    Some(output_dir) => {
+      let keys: Vec<&str> = [Some(&function_key), layer_key.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect();
+      output_excludes(&args.input_path, output_dir, &keys)?
+    }
+    None => Vec::new(),
+  };
+  let mut include = args.include.clone();
+  let mut exclude = [args.exclude.as_slice(), &outputs].concat();
+  let (layer, packages) = match layer_key {
+    Some(layer_key) => {
+      exclude.append(args.layer_globs.clone().as_mut());
+      let layer = Artifact::build(
+        layer_key,
+        &args.input_path,
+        &[args.layer_globs.as_slice(), &patterns].concat(),
+        &[junk.as_slice(), &outputs].concat(),
+        &packages,
+        None,
+        &layer_zip_options,
+      )?;
+      (Some(layer), Vec::new())
+    }
+    None => {
+      include.extend(patterns);
+      exclude.extend(junk);
+      (None, packages)
     }
+  };
+  let function = Artifact::build(
+    function_key,
+    &args.input_path,
+    &include,
+    &exclude,
+    &packages,
+    symlink,
+    &fn_zip_options,
+  )?;
+  let output_dir = args.output_dir.as_ref().map(Path::new);
+  if args.dry_run {
+    for artifact in layer.iter().chain([&function]) {
+      let destinations: Vec<String> = output_dir
+        .map(|dir| artifact.local_path(dir).display().to_string())
+        .into_iter()
+        .chain(destinations.iter().map(|dest| {
+          let region = dest
+            .region
+            .as_deref()
+            .unwrap_or("region looked up on upload");
+          s3_destination(&dest.bucket, region, artifact)
+        }))
+        .collect();
+      println!("{}", artifact.plan(&destinations)?);
+    }
+    return Ok(());
   }
-  for (ix, bucket) in args.buckets.iter().enumerate() {
-    let file_list = search(&args.input_path, &args.include, &exclude);
-    let buffer = create_zip(
-      &args.input_path,
-      file_list,
-      match args.symlink_node_modules {
-        true => Some(SymLink {
-          target: "/opt/nodejs/node_modules".to_string(),
-          path: "node_modules".to_string(),
-        }),
-        false => None,
-      },
-    );
-    s3_upload(&args.regions[ix], bucket, &fn_object_key, buffer).await;
+  for artifact in layer.iter().chain([&function]) {
+    println!("{}", artifact);
+    if let Some(dir) = output_dir {
+      println!("wrote {}", artifact.write_to(dir)?.display());
+    }
+  }
+  if destinations.is_empty() {
+    return Ok(());
+  }
+  let upload_options = S3UploadOptions {
+    force: args.force,
+    multipart_threshold: args.multipart_threshold * MIB,
+    part_size: args.part_size * MIB,
+    part_concurrency: args.part_concurrency.into(),
+    retry: RetryPolicy {
+      max_attempts: args.retry_attempts,
+      base_delay: Duration::from_millis(args.retry_base_delay),
+      max_delay: Duration::from_millis(args.retry_max_delay),
+      jitter: args.retry_jitter,
+    },
+  };
+  let shared_config = aws_config::load_from_env().await;
+  let lookup_clients = s3_clients(&shared_config, &[LOOKUP_REGION.to_string()]);
+  let lookups = resolve_regions(
+    &lookup_clients[LOOKUP_REGION],
+    destinations,
+    &upload_options.retry,
+  )
+  .await;
+  let mut reports = Vec::new();
+  let mut resolved = Vec::new();
+  for (bucket, region) in lookups {
+    match region {
+      Ok(region) => resolved.push((bucket, region)),
+      Err(e) => reports.push(UploadReport {
+        destination: format!("s3://{} (region lookup)", bucket),
+        result: Err(e),
+      }),
+    }
   }
+  let destinations = resolved;
+  let regions: Vec<String> = destinations
+    .iter()
+    .map(|(_, region)| region.clone())
+    .collect();
+  let clients = s3_clients(&shared_config, &regions);
+  let uploads = layer.iter().chain([&function]).flat_map(|artifact| {
+    let (clients, upload_options) = (&clients, &upload_options);
+    destinations.iter().map(move |(bucket, region)| async move {
+      UploadReport {
+        destination: s3_destination(bucket, region, artifact),
+        result: s3_upload(&clients[region], bucket, artifact, upload_options).await,
+      }
+    })
+  });
+  reports.extend(run_uploads(uploads, args.concurrency.into()).await);
+  finish_uploads(reports)
 }
 
 #[cfg(test)]
 mod tests {
+  use crate::zip::ArchiveArgs;
   use aws_sdk_s3::{config::Region, Client};
   use rand::Rng;
   use zip::ZipArchive;
 
   use super::*;
+  use crate::testing::temp_dir;
   use std::{
     env,
     fs::File,
@@ -104,6 +431,7 @@ mod tests {
 
     let args = AWSArgs {
       regions: vec!["eu-west-2".to_string()],
+      dest: vec![],
       buckets: vec!["fn-push-testing".to_string()],
       function_key: "aws-test".to_string(),
       include: vec![get_file_path("aws.rs")],
@@ -111,11 +439,27 @@ mod tests {
       input_path: ".".to_string(),
       layer_key: None,
       layer_globs: vec![],
+      layer_runtime: None,
+      layer_prefix: None,
+      prune_node_modules: false,
+      prune_junk: false,
       version_suffix: Some(random_string.clone()),
       root_dir: None,
+      archive: ArchiveArgs::default(),
+      concurrency: 4,
+      force: false,
+      dry_run: false,
+      output_dir: None,
+      multipart_threshold: 16,
+      part_size: 8,
+      part_concurrency: 4,
+      retry_attempts: 4,
+      retry_base_delay: 250,
+      retry_max_delay: 20000,
+      retry_jitter: true,
       symlink_node_modules: false,
     };
-    push_aws(args).await;
+    push_aws(args).await.unwrap();
     let object_name = format!("aws-test-{}.zip", random_string);
     println!("object_name: {}", object_name);
     let region = "eu-west-2".to_string();
@@ -144,7 +488,7 @@ mod tests {
     let mut zip = ZipArchive::new(Cursor::new(zip_object)).unwrap();
 
     // Extract the file from the archive
-    let mut file_entry = zip.by_name(get_file_path("aws.rs").as_str()).unwrap();
+    let mut file_entry = zip.by_name("src/aws.rs").unwrap();
 
     // Copy it to the buffer
     io::copy(&mut file_entry, &mut content_buf).unwrap();
@@ -166,6 +510,7 @@ mod tests {
 
     let args = AWSArgs {
       regions: vec!["eu-west-2".to_string()],
+      dest: vec![],
       buckets: vec!["fn-push-testing".to_string()],
       function_key: function_key.clone(),
       include: vec![get_file_path("aws.rs")],
@@ -173,11 +518,27 @@ mod tests {
       input_path: ".".to_string(),
       layer_key: None,
       layer_globs: vec![],
+      layer_runtime: None,
+      layer_prefix: None,
+      prune_node_modules: false,
+      prune_junk: false,
       version_suffix: None,
       root_dir: None,
+      archive: ArchiveArgs::default(),
+      concurrency: 4,
+      force: false,
+      dry_run: false,
+      output_dir: None,
+      multipart_threshold: 16,
+      part_size: 8,
+      part_concurrency: 4,
+      retry_attempts: 4,
+      retry_base_delay: 250,
+      retry_max_delay: 20000,
+      retry_jitter: true,
       symlink_node_modules: false,
     };
-    push_aws(args).await;
+    push_aws(args).await.unwrap();
     let object_name = format!("{}.zip", function_key);
     println!("object_name: {}", object_name);
     let region = "eu-west-2".to_string();
@@ -206,7 +567,7 @@ mod tests {
     let mut zip = ZipArchive::new(Cursor::new(zip_object)).unwrap();
 
     // Extract the file from the archive
-    let mut file_entry = zip.by_name(get_file_path("aws.rs").as_str()).unwrap();
+    let mut file_entry = zip.by_name("src/aws.rs").unwrap();
 
     // Copy it to the buffer
     io::copy(&mut file_entry, &mut content_buf).unwrap();
@@ -228,6 +589,7 @@ mod tests {
 
     let args = AWSArgs {
       regions: vec!["eu-west-2".to_string()],
+      dest: vec![],
       buckets: vec!["fn-push-testing".to_string()],
       function_key: "aws-test".to_string(),
       include: vec![get_file_path("*.rs")],
@@ -235,11 +597,27 @@ mod tests {
       input_path: ".".to_string(),
       layer_key: Some("aws-layer".to_string()),
       layer_globs: vec![get_file_path("aws.rs")],
+      layer_runtime: None,
+      layer_prefix: None,
+      prune_node_modules: false,
+      prune_junk: false,
       version_suffix: Some(random_string.clone()),
       root_dir: None,
+      archive: ArchiveArgs::default(),
+      concurrency: 4,
+      force: false,
+      dry_run: false,
+      output_dir: None,
+      multipart_threshold: 16,
+      part_size: 8,
+      part_concurrency: 4,
+      retry_attempts: 4,
+      retry_base_delay: 250,
+      retry_max_delay: 20000,
+      retry_jitter: true,
       symlink_node_modules: false,
     };
-    push_aws(args).await;
+    push_aws(args).await.unwrap();
     let fn_object_name = format!("aws-test-{}.zip", random_string);
     let layer_object_name = format!("aws-layer-{}.zip", random_string);
     println!("object_name: {}", fn_object_name);
@@ -282,7 +660,7 @@ mod tests {
     let mut layer_zip = ZipArchive::new(Cursor::new(layer_zip_object)).unwrap();
 
     // Extract the file from the archive
-    let mut file_entry = layer_zip.by_name(get_file_path("aws.rs").as_str()).unwrap();
+    let mut file_entry = layer_zip.by_name("src/aws.rs").unwrap();
 
     // Copy it to the buffer
     io::copy(&mut file_entry, &mut layer_content_buf).unwrap();
@@ -301,8 +679,301 @@ mod tests {
     let mut fn_zip = ZipArchive::new(Cursor::new(fn_zip_object)).unwrap();
 
     // Extract the file from the archive
-    let fn_file_entry = fn_zip.by_name(get_file_path("aws.rs").as_str());
+    let fn_file_entry = fn_zip.by_name("src/aws.rs");
 
     assert!(fn_file_entry.is_err());
   }
+
+  fn layer_args(layer_runtime: Option<LayerRuntime>, layer_prefix: Option<&str>) -> AWSArgs {
+    AWSArgs {
+      regions: vec!["eu-west-2".to_string()],
+      dest: vec![],
+      buckets: vec!["fn-push-testing".to_string()],
+      function_key: "aws-test".to_string(),
+      include: vec![get_file_path("*.rs")],
+      exclude: vec![],
+      input_path: ".".to_string(),
+      layer_key: Some("aws-layer".to_string()),
+      layer_globs: vec!["node_modules/**".to_string()],
+      layer_runtime,
+      layer_prefix: layer_prefix.map(String::from),
+      prune_node_modules: false,
+      prune_junk: false,
+      version_suffix: None,
+      root_dir: None,
+      archive: ArchiveArgs::default(),
+      concurrency: 4,
+      force: false,
+      dry_run: false,
+      output_dir: None,
+      multipart_threshold: 16,
+      part_size: 8,
+      part_concurrency: 4,
+      retry_attempts: 4,
+      retry_base_delay: 250,
+      retry_max_delay: 20000,
+      retry_jitter: true,
+      symlink_node_modules: true,
+    }
+  }
+
+  #[test]
+  fn layer_files_are_placed_under_the_runtime_dir() {
+    let no_symlink = AWSArgs {
+      symlink_node_modules: false,
+      ..layer_args(None, None)
+    };
+    assert_eq!(layer_root_dir(&no_symlink).unwrap(), None);
+    assert_eq!(
+      layer_root_dir(&layer_args(Some(LayerRuntime::Nodejs), None)).unwrap(),
+      Some(PathBuf::from("nodejs"))
+    );
+    assert_eq!(
+      layer_root_dir(&layer_args(Some(LayerRuntime::Java), None)).unwrap(),
+      Some(PathBuf::from("java").join("lib"))
+    );
+    assert_eq!(
+      layer_root_dir(&layer_args(Some(LayerRuntime::Custom), Some("opt/deps"))).unwrap(),
+      Some(PathBuf::from("opt").join("deps"))
+    );
+    assert!(layer_root_dir(&layer_args(Some(LayerRuntime::Custom), None)).is_err());
+    assert!(layer_root_dir(&layer_args(Some(LayerRuntime::Custom), Some("../deps"))).is_err());
+  }
+
+  #[test]
+  fn symlink_points_at_the_layer_node_modules() {
+    // the layer's node_modules goes where the symlink points even without a layer runtime
+    let args = layer_args(None, None);
+    let layer_root = layer_root_dir(&args).unwrap();
+    assert_eq!(layer_root, Some(PathBuf::from("nodejs")));
+    let link = node_modules_symlink(&args, &layer_root).unwrap();
+    assert_eq!(link.target, "/opt/nodejs/node_modules");
+    assert_eq!(link.path, "node_modules");
+
+    let args = layer_args(Some(LayerRuntime::Custom), Some("deps/js"));
+    let link = node_modules_symlink(&args, &layer_root_dir(&args).unwrap()).unwrap();
+    assert_eq!(link.target, "/opt/deps/js/node_modules");
+
+    let args = layer_args(Some(LayerRuntime::Python), None);
+    assert!(node_modules_symlink(&args, &layer_root_dir(&args).unwrap()).is_err());
+  }
+
+  #[tokio::test]
+  async fn dry_run_never_touches_the_cloud() {
+    let args = AWSArgs {
+      buckets: vec!["no-such-bucket".to_string()],
+      layer_key: None,
+      dry_run: true,
+      ..layer_args(None, None)
+    };
+    push_aws(args).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn layers_only_hold_production_packages() {
+    let (_guard, dir) = temp_dir();
+    let files = [
+      ("index.js", ""),
+      ("package.json", r#"{"dependencies": {"a": "^1.0.0"}}"#),
+      (
+        "package-lock.json",
+        r#"{"lockfileVersion": 3, "packages": {
+          "node_modules/a": {"version": "1.0.0"},
+          "node_modules/dev": {"version": "1.0.0", "dev": true}
+        }}"#,
+      ),
+      ("node_modules/.package-lock.json", "{}"),
+      ("node_modules/a/package.json", "{}"),
+      ("node_modules/a/index.js", ""),
+      ("node_modules/a/README.md", ""),
+      ("node_modules/a/test/index.js", ""),
+      ("node_modules/dev/index.js", ""),
+    ];
+    for (name, contents) in files {
+      let path = dir.join(name);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, contents).unwrap();
+    }
+    let output_dir = dir.join("dist");
+    let args = AWSArgs {
+      regions: vec![],
+      buckets: vec![],
+      include: vec!["index.js".to_string()],
+      input_path: dir.display().to_string(),
+      prune_node_modules: true,
+      prune_junk: true,
+      output_dir: Some(output_dir.display().to_string()),
+      symlink_node_modules: false,
+      ..layer_args(None, None)
+    };
+    push_aws(args).await.unwrap();
+    let layer = ZipArchive::new(File::open(output_dir.join("aws-layer.zip")).unwrap()).unwrap();
+    let mut names: Vec<&str> = layer.file_names().collect();
+    names.sort();
+    assert_eq!(
+      names,
+      ["node_modules/a/index.js", "node_modules/a/package.json"]
+    );
+  }
+
+  #[tokio::test]
+  async fn layers_keep_node_modules_that_are_gitignored() {
+    let (_guard, dir) = temp_dir();
+    let files = [
+      ("index.js", ""),
+      (".gitignore", "node_modules\ndist\n"),
+      ("node_modules/a/index.js", ""),
+    ];
+    for (name, contents) in files {
+      let path = dir.join(name);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(path, contents).unwrap();
+    }
+    let output_dir = dir.join("dist");
+    let args = AWSArgs {
+      regions: vec![],
+      buckets: vec![],
+      include: vec!["index.js".to_string()],
+      input_path: dir.display().to_string(),
+      archive: ArchiveArgs {
+        ignore_files: true,
+        ..Default::default()
+      },
+      output_dir: Some(output_dir.display().to_string()),
+      symlink_node_modules: false,
+      ..layer_args(None, None)
+    };
+    push_aws(args).await.unwrap();
+    let layer = ZipArchive::new(File::open(output_dir.join("aws-layer.zip")).unwrap()).unwrap();
+    assert_eq!(
+      layer.file_names().collect::<Vec<&str>>(),
+      ["node_modules/a/index.js"]
+    );
+  }
+
+  fn dest(bucket: &str, region: Option<&str>) -> Destination {
+    Destination {
+      bucket: bucket.to_string(),
+      region: region.map(String::from),
+    }
+  }
+
+  #[tokio::test]
+  async fn failed_region_lookups_only_fail_their_bucket() {
+    let config = aws_sdk_s3::Config::builder()
+      .region(aws_sdk_s3::config::Region::new(LOOKUP_REGION))
+      .credentials_provider(aws_sdk_s3::config::Credentials::new(
+        "key", "secret", None, None, "test",
+      ))
+      .endpoint_url("http://127.0.0.1:1")
+      .build();
+    let client = Client::from_conf(config);
+    let retry = RetryPolicy {
+      max_attempts: 1,
+      ..RetryPolicy::default()
+    };
+    let resolved = resolve_regions(
+      &client,
+      vec![dest("known", Some("eu-west-2")), dest("unknown", None)],
+      &retry,
+    )
+    .await;
+    assert_eq!(resolved[0].0, "known");
+    assert_eq!(resolved[0].1.as_ref().unwrap(), "eu-west-2");
+    assert_eq!(resolved[1].0, "unknown");
+    assert!(resolved[1].1.is_err());
+  }
+
+  #[test]
+  fn buckets_are_paired_with_regions() {
+    let args = AWSArgs {
+      buckets: vec!["a".to_string(), "b".to_string()],
+      regions: vec!["eu-west-1".to_string(), "us-gov-west-1".to_string()],
+      ..layer_args(None, None)
+    };
+    assert_eq!(
+      destinations(&args).unwrap(),
+      vec![
+        dest("a", Some("eu-west-1")),
+        dest("b", Some("us-gov-west-1"))
+      ]
+    );
+
+    // a single region is used for every bucket
+    let args = AWSArgs {
+      regions: vec!["eu-west-2".to_string()],
+      ..args
+    };
+    assert_eq!(
+      destinations(&args).unwrap(),
+      vec![dest("a", Some("eu-west-2")), dest("b", Some("eu-west-2"))]
+    );
+
+    // and without any regions they're looked up
+    let args = AWSArgs {
+      regions: vec![],
+      ..args
+    };
+    assert_eq!(
+      destinations(&args).unwrap(),
+      vec![dest("a", None), dest("b", None)]
+    );
+
+    let args = AWSArgs {
+      buckets: vec!["a".to_string(), "b".to_string(), "c".to_string()],
+      regions: vec!["eu-west-1".to_string(), "eu-west-2".to_string()],
+      ..args
+    };
+    assert!(matches!(destinations(&args), Err(Error::InvalidArgs(_))));
+
+    for region in ["eu-west", "EU-WEST-1", "euwest1", "eu--1", "west-1"] {
+      let args = AWSArgs {
+        buckets: vec!["a".to_string()],
+        regions: vec![region.to_string()],
+        ..layer_args(None, None)
+      };
+      assert!(destinations(&args).is_err(), "{} is not a region", region);
+    }
+  }
+
+  #[test]
+  fn dests_name_a_bucket_and_optionally_its_region() {
+    let args = AWSArgs {
+      buckets: vec!["a".to_string()],
+      regions: vec!["eu-west-1".to_string()],
+      dest: vec!["b@us-east-2".to_string(), "c".to_string()],
+      ..layer_args(None, None)
+    };
+    assert_eq!(
+      destinations(&args).unwrap(),
+      vec![
+        dest("a", Some("eu-west-1")),
+        dest("b", Some("us-east-2")),
+        dest("c", None)
+      ]
+    );
+    assert!(parse_dest("@eu-west-1").is_err());
+    assert!(parse_dest("b@").is_err());
+    assert!(parse_dest("b@eu-west").is_err());
+    assert_eq!(
+      parse_dest("b@eusc-de-east-1").unwrap(),
+      dest("b", Some("eusc-de-east-1"))
+    );
+  }
+
+  #[tokio::test]
+  async fn invalid_keys_fail_before_any_work() {
+    let args = AWSArgs {
+      function_key: "my function".to_string(),
+      dry_run: true,
+      ..layer_args(None, None)
+    };
+    assert!(matches!(push_aws(args).await, Err(Error::InvalidArgs(_))));
+    let args = AWSArgs {
+      version_suffix: Some("".to_string()),
+      dry_run: true,
+      ..layer_args(None, None)
+    };
+    assert!(matches!(push_aws(args).await, Err(Error::InvalidArgs(_))));
+  }
 }
@@ -0,0 +1,143 @@
+use std::{io, path::PathBuf};
+
+/// A specialised `Result` type for push-fns operations
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong while searching for, zipping up, or uploading function assets
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  /// The CLI arguments were invalid or inconsistent
+  #[error("invalid arguments: {0}")]
+  InvalidArgs(String),
+
+  /// A glob pattern could not be parsed
+  #[error("invalid glob pattern {pattern:?}: {source}")]
+  Glob {
+    /// The pattern that failed to parse
+    pattern: String,
+    /// The underlying parse error
+    source: Box<dyn std::error::Error + Send + Sync>,
+  },
+
+  /// An ignore file could not be read or parsed
+  #[error("invalid ignore file {path:?}: {source}")]
+  Ignore {
+    /// The ignore file that could not be read or parsed
+    path: PathBuf,
+    /// The underlying error
+    source: ignore::Error,
+  },
+
+  /// None of the include globs matched any files
+  #[error("no files matched the include globs in {path:?}")]
+  NoFilesMatched {
+    /// The input path that was searched
+    path: String,
+  },
+
+  /// A file or directory could not be read or written
+  #[error("unable to access {path:?}: {source}")]
+  Io {
+    /// The path that could not be read or written
+    path: PathBuf,
+    /// The underlying IO error
+    source: io::Error,
+  },
+
+  /// A package.json or lockfile could not be parsed
+  #[error("unable to parse {path:?}: {reason}")]
+  Package {
+    /// The file that could not be parsed
+    path: PathBuf,
+    /// Why it could not be parsed
+    reason: String,
+  },
+
+  /// The zip archive could not be written
+  #[error("unable to write zip archive: {0}")]
+  Archive(#[from] zip::result::ZipError),
+
+  /// Credentials could not be loaded or were rejected by the cloud provider
+  #[error("authentication failed: {0}")]
+  Auth(String),
+
+  /// A request to AWS S3 failed
+  #[error("AWS S3 request failed: {0}")]
+  Aws(String),
+
+  /// A request to Google Cloud Storage failed
+  #[error("Google Cloud Storage request failed: {0}")]
+  Gcs(String),
+}
+
+impl Error {
+  /// Builds an [`Error::Io`] for the given path
+  pub fn io<P: Into<PathBuf>>(path: P, source: io::Error) -> Self {
+    Error::Io {
+      path: path.into(),
+      source,
+    }
+  }
+
+  /// Builds an [`Error::Package`] for the given path
+  pub fn package<P: Into<PathBuf>>(path: P, reason: impl std::fmt::Display) -> Self {
+    Error::Package {
+      path: path.into(),
+      reason: reason.to_string(),
+    }
+  }
+
+  /// The process exit code for this error, distinct for each kind of failure so that CI can
+  /// tell them apart
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      Error::InvalidArgs(_) => 2,
+      Error::Glob { .. } => 3,
+      Error::NoFilesMatched { .. } => 4,
+      Error::Io { .. } => 5,
+      Error::Archive(_) => 6,
+      Error::Auth(_) => 7,
+      Error::Aws(_) => 8,
+      Error::Gcs(_) => 9,
+      Error::Ignore { .. } => 10,
+      Error::Package { .. } => 11,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn exit_codes_are_distinct() {
+    let errors = [
+      Error::InvalidArgs(String::new()),
+      Error::Glob {
+        pattern: "[".to_string(),
+        source: glob::Pattern::new("[").unwrap_err().into(),
+      },
+      Error::NoFilesMatched {
+        path: ".".to_string(),
+      },
+      Error::io(".", io::Error::from(io::ErrorKind::NotFound)),
+      Error::Archive(zip::result::ZipError::FileNotFound),
+      Error::Auth(String::new()),
+      Error::Aws(String::new()),
+      Error::Gcs(String::new()),
+      Error::Ignore {
+        path: PathBuf::from(".gitignore"),
+        source: ignore::Error::Glob {
+          glob: None,
+          err: String::new(),
+        },
+      },
+      Error::package("package.json", ""),
+    ];
+    let mut codes: Vec<i32> = errors.iter().map(Error::exit_code).collect();
+    codes.sort();
+    codes.dedup();
+    assert_eq!(codes.len(), errors.len());
+    assert!(!codes.contains(&0) && !codes.contains(&1));
+  }
+}
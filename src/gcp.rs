@@ -1,14 +1,33 @@
+use std::{path::Path, time::Duration};
+
+pub use crate::args::GCPArgs;
 use crate::{
-  args::GCPArgs, search::search, upload::google_cloud_storage::cs_upload, zip::create_zip,
+  artifact::{object_key, output_excludes, validate_key, Artifact},
+  error::Result,
+  upload::{
+    finish_uploads,
+    google_cloud_storage::{cs_client, cs_upload, CsUploadOptions},
+    retry::RetryPolicy,
+    run_uploads, UploadReport, MIB,
+  },
+  zip::{validate_root_dir, ZipOptions},
 };
 
+/// Describes where an artifact is uploaded to in one bucket
+fn gs_destination(bucket: &str, artifact: &Artifact) -> String {
+  format!("gs://{}/{}", bucket, artifact.key)
+}
+
 /// Zips up function assets and uploads them to Google Cloud Storage for use in Cloud Functions.
 ///
 /// Example
 /// ```rust
 /// use google_cloud_default::WithAuthExt;
 /// use google_cloud_storage::{client::{ClientConfig, Client}, http::objects::{get::GetObjectRequest, download::Range}};
-/// use push_fn_lib::gcp::{GCPArgs, push_gcs};
+/// use push_fn_lib::{
+///   gcp::{GCPArgs, push_gcs},
+///   zip::ArchiveArgs,
+/// };
 ///
 /// async fn do_upload() {
 ///   let version = "1.0.0".to_string();
@@ -20,24 +39,99 @@ use crate::{
 ///     input_path: ".".to_string(),
 ///     version_suffix: Some(version.clone()),
 ///     root_dir: None,
+///     archive: ArchiveArgs::default(),
+///     concurrency: 4,
+///     force: false,
+///     dry_run: false,
+///     output_dir: None,
+///     resumable_threshold: 8,
+///     chunk_size: 8,
+///     retry_attempts: 4,
+///     retry_base_delay: 250,
+///     retry_max_delay: 20000,
+///     retry_jitter: true,
 ///   };
-///   push_gcs(args).await;
+///   push_gcs(args).await.unwrap();
 /// }
 /// ```
-pub async fn push_gcs(args: GCPArgs) {
-  let fn_object_key = match args.version_suffix.clone() {
-    Some(version) => format!("{}-{}.zip", args.function_key, version),
-    None => format!("{}.zip", args.function_key),
+pub async fn push_gcs(args: GCPArgs) -> Result<()> {
+  validate_key("function key", &args.function_key)?;
+  if let Some(version_suffix) = &args.version_suffix {
+    validate_key("version suffix", version_suffix)?;
+  }
+  let root_dir = match &args.root_dir {
+    Some(root_dir) => validate_root_dir(root_dir)?,
+    None => None,
   };
-  for bucket in args.buckets.iter() {
-    let file_list = search(&args.input_path, &args.include, &args.exclude);
-    let buffer = create_zip(&args.input_path, file_list, None);
-    cs_upload(bucket, &fn_object_key, buffer).await;
+  let zip_options = ZipOptions::new(&args.archive, root_dir)?;
+  let function_key = object_key(&args.function_key, &args.version_suffix);
+  // an archive written by an earlier run isn't packed into this one
+  let mut exclude = args.exclude.clone();
+  if let Some(output_dir) = &args.output_dir {
+    exclude.extend(output_excludes(
+      &args.input_path,
+      output_dir,
+      &[&function_key],
+    )?);
+  }
+  let function = Artifact::build(
+    function_key,
+    &args.input_path,
+    &args.include,
+    &exclude,
+    &[],
+    None,
+    &zip_options,
+  )?;
+  let output_dir = args.output_dir.as_ref().map(Path::new);
+  if args.dry_run {
+    let destinations: Vec<String> = output_dir
+      .map(|dir| function.local_path(dir).display().to_string())
+      .into_iter()
+      .chain(
+        args
+          .buckets
+          .iter()
+          .map(|bucket| gs_destination(bucket, &function)),
+      )
+      .collect();
+    println!("{}", function.plan(&destinations)?);
+    return Ok(());
+  }
+  println!("{}", function);
+  if let Some(dir) = output_dir {
+    println!("wrote {}", function.write_to(dir)?.display());
+  }
+  if args.buckets.is_empty() {
+    return Ok(());
   }
+  let upload_options = CsUploadOptions {
+    force: args.force,
+    resumable_threshold: args.resumable_threshold * MIB,
+    chunk_size: args.chunk_size * MIB,
+    retry: RetryPolicy {
+      max_attempts: args.retry_attempts,
+      base_delay: Duration::from_millis(args.retry_base_delay),
+      max_delay: Duration::from_millis(args.retry_max_delay),
+      jitter: args.retry_jitter,
+    },
+  };
+  let client = cs_client().await?;
+  let uploads = args.buckets.iter().map(|bucket| {
+    let (client, function, upload_options) = (&client, &function, &upload_options);
+    async move {
+      UploadReport {
+        destination: gs_destination(bucket, function),
+        result: cs_upload(client, bucket, function, upload_options).await,
+      }
+    }
+  });
+  finish_uploads(run_uploads(uploads, args.concurrency.into()).await)
 }
 
 #[cfg(test)]
 mod tests {
+  use crate::zip::ArchiveArgs;
   use google_cloud_default::WithAuthExt;
   use google_cloud_storage::{
     client::{Client, ClientConfig},
@@ -47,6 +141,7 @@ mod tests {
   use zip::ZipArchive;
 
   use super::*;
+  use crate::testing::temp_dir;
   use std::{
     env,
     fs::File,
@@ -84,8 +179,19 @@ mod tests {
       input_path: ".".to_string(),
       version_suffix: Some(random_string.clone()),
       root_dir: None,
+      archive: ArchiveArgs::default(),
+      concurrency: 4,
+      force: false,
+      dry_run: false,
+      output_dir: None,
+      resumable_threshold: 8,
+      chunk_size: 8,
+      retry_attempts: 4,
+      retry_base_delay: 250,
+      retry_max_delay: 20000,
+      retry_jitter: true,
     };
-    push_gcs(args).await;
+    push_gcs(args).await.unwrap();
     let object = format!("gcp-test-{}.zip", random_string);
     println!("object_name: {}", object);
     let bucket = "fn-push-testing".to_string();
@@ -117,7 +223,7 @@ mod tests {
     let mut zip = ZipArchive::new(Cursor::new(zip_object)).unwrap();
 
     // Extract the file from the archive
-    let mut file_entry = zip.by_name(get_file_path().as_str()).unwrap();
+    let mut file_entry = zip.by_name("src/gcp.rs").unwrap();
 
     // Copy it to the buffer
     io::copy(&mut file_entry, &mut content_buf).unwrap();
@@ -145,8 +251,19 @@ mod tests {
       input_path: ".".to_string(),
       version_suffix: None,
       root_dir: None,
+      archive: ArchiveArgs::default(),
+      concurrency: 4,
+      force: false,
+      dry_run: false,
+      output_dir: None,
+      resumable_threshold: 8,
+      chunk_size: 8,
+      retry_attempts: 4,
+      retry_base_delay: 250,
+      retry_max_delay: 20000,
+      retry_jitter: true,
     };
-    push_gcs(args).await;
+    push_gcs(args).await.unwrap();
     let object = format!("{}.zip", function_key);
     println!("object_name: {}", object);
     let bucket = "fn-push-testing".to_string();
@@ -178,7 +295,7 @@ mod tests {
     let mut zip = ZipArchive::new(Cursor::new(zip_object)).unwrap();
 
     // Extract the file from the archive
-    let mut file_entry = zip.by_name(get_file_path().as_str()).unwrap();
+    let mut file_entry = zip.by_name("src/gcp.rs").unwrap();
 
     // Copy it to the buffer
     io::copy(&mut file_entry, &mut content_buf).unwrap();
@@ -193,4 +310,66 @@ mod tests {
     // Compare the content we got from the archive to the content we got by reading from disk
     assert_eq!(file_buf, content_buf.into_inner());
   }
+
+  #[tokio::test]
+  async fn archives_can_be_written_without_any_buckets() {
+    let (_guard, output_dir) = temp_dir();
+    let args = GCPArgs {
+      buckets: vec![],
+      function_key: "gcp-local".to_string(),
+      include: vec![get_file_path()],
+      exclude: vec![],
+      input_path: ".".to_string(),
+      version_suffix: Some("1.0.0".to_string()),
+      root_dir: None,
+      archive: ArchiveArgs::default(),
+      concurrency: 4,
+      force: false,
+      dry_run: false,
+      output_dir: Some(output_dir.display().to_string()),
+      resumable_threshold: 8,
+      chunk_size: 8,
+      retry_attempts: 4,
+      retry_base_delay: 250,
+      retry_max_delay: 20000,
+      retry_jitter: true,
+    };
+    push_gcs(args).await.unwrap();
+    let zip_file = File::open(output_dir.join("gcp-local-1.0.0.zip")).unwrap();
+    let mut zip = ZipArchive::new(zip_file).unwrap();
+    assert!(zip.by_name("src/gcp.rs").is_ok());
+  }
+
+  #[tokio::test]
+  async fn earlier_archives_are_left_out_of_the_next_one() {
+    let (_guard, dir) = temp_dir();
+    std::fs::write(dir.join("index.js"), "").unwrap();
+    let output_dir = dir.join("out");
+    for _ in 0..2 {
+      let args = GCPArgs {
+        buckets: vec![],
+        function_key: "fn".to_string(),
+        include: vec!["**".to_string()],
+        exclude: vec![],
+        input_path: dir.display().to_string(),
+        version_suffix: None,
+        root_dir: None,
+        archive: ArchiveArgs::default(),
+        concurrency: 4,
+        force: false,
+        dry_run: false,
+        output_dir: Some(output_dir.display().to_string()),
+        resumable_threshold: 8,
+        chunk_size: 8,
+        retry_attempts: 4,
+        retry_base_delay: 250,
+        retry_max_delay: 20000,
+        retry_jitter: true,
+      };
+      push_gcs(args).await.unwrap();
+    }
+    let zip_file = File::open(output_dir.join("fn.zip")).unwrap();
+    let zip = ZipArchive::new(zip_file).unwrap();
+    assert_eq!(zip.file_names().collect::<Vec<_>>(), ["index.js"]);
+  }
 }
@@ -0,0 +1,41 @@
+#![doc = include_str!("../README.md")]
+#![deny(missing_docs)]
+#![doc(issue_tracker_base_url = "https://github.com/bbeesley/push-fns/issues/")]
+
+/// The CLI arguments
+pub mod args;
+/// Zip archives that are built once and uploaded to every destination
+pub mod artifact;
+
+/// Functions for uploading to AWS S3
+pub mod aws;
+/// The error type shared by every stage of the pipeline
+pub mod error;
+/// Functions for uploading to GCP Cloud Storage
+pub mod gcp;
+/// Functions for working out which installed node_modules packages are needed in production
+pub mod node_modules;
+/// Functions for searching the filesystem based on include and exclude globs
+pub mod search;
+/// Generic upload functions for S3 and GCS
+pub mod upload;
+/// Functions for adding a list of files to a zip archive
+pub mod zip;
+
+pub use crate::error::{Error, Result};
+
+/// Helpers shared by the unit tests
+#[cfg(test)]
+pub(crate) mod testing {
+  use std::path::PathBuf;
+
+  use tempfile::TempDir;
+
+  /// A new temporary directory, removed when the returned guard is dropped even if the test fails,
+  /// along with its canonical path so that it can be compared with the paths found in it
+  pub(crate) fn temp_dir() -> (TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().canonicalize().unwrap();
+    (dir, path)
+  }
+}
@@ -0,0 +1,941 @@
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  ffi::OsStr,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use serde_json::Value as Json;
+use serde_yaml::Value as Yaml;
+
+use crate::{
+  error::{Error, Result},
+  search::{search, PatternList},
+  zip::{self, SymlinkMode, ZipSource},
+};
+
+/// Globs for files in node_modules that are almost never needed at runtime: docs, type
+/// declarations, tests and CI config
+pub const JUNK: [&str; 10] = [
+  "node_modules/**/*.md",
+  "node_modules/**/*.markdown",
+  "node_modules/**/*.d.ts",
+  "node_modules/**/*.d.mts",
+  "node_modules/**/*.d.cts",
+  "node_modules/**/test",
+  "node_modules/**/tests",
+  "node_modules/**/__tests__",
+  "node_modules/**/.github",
+  "node_modules/**/.circleci",
+];
+
+/// The package.json sections whose packages are needed at runtime
+const PRODUCTION_SECTIONS: [&str; 2] = ["dependencies", "optionalDependencies"];
+
+/// The package.json sections whose packages a dependency needs at runtime, where its peer
+/// dependencies are installed alongside it
+const RUNTIME_SECTIONS: [&str; 3] = ["dependencies", "optionalDependencies", "peerDependencies"];
+
+/// The lockfiles that production dependencies can be worked out from
+const LOCKFILES: [&str; 4] = [
+  "npm-shrinkwrap.json",
+  "package-lock.json",
+  "yarn.lock",
+  "pnpm-lock.yaml",
+];
+
+/// The name and version of a package
+type PackageId = (String, String);
+
+/// Reads a JSON file, failing if it is missing or malformed
+fn read_json(path: &Path) -> Result<Json> {
+  let text = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+  serde_json::from_str(&text).map_err(|e| Error::package(path, e))
+}
+
+/// The names and version ranges in the given sections of a package.json or lockfile entry
+fn json_deps<'a>(manifest: &'a Json, sections: &[&str]) -> Vec<(&'a str, &'a str)> {
+  sections
+    .iter()
+    .filter_map(|section| manifest.get(section)?.as_object())
+    .flatten()
+    .filter_map(|(name, range)| Some((name.as_str(), range.as_str()?)))
+    .collect()
+}
+
+/// The production packages recorded in a package-lock.json (or npm-shrinkwrap.json), as paths
+/// relative to the input path. Lockfiles from npm 7 on list every installed path and flag the
+/// ones only needed for development, while older ones nest the same flags in a dependency tree.
+fn npm_packages(lock: &Json) -> Vec<String> {
+  if let Some(packages) = lock.get("packages").and_then(Json::as_object) {
+    let flagged = |entry: &Json, flag| entry.get(flag).and_then(Json::as_bool) == Some(true);
+    return packages
+      .iter()
+      .filter(|(path, entry)| {
+        path.starts_with("node_modules/")
+          && !flagged(entry, "dev")
+          && !flagged(entry, "devOptional")
+          && !flagged(entry, "extraneous")
+      })
+      .map(|(path, _)| path.to_string())
+      .collect();
+  }
+  let mut paths = Vec::new();
+  let mut pending = vec![(String::new(), lock)];
+  while let Some((prefix, parent)) = pending.pop() {
+    let Some(dependencies) = parent.get("dependencies").and_then(Json::as_object) else {
+      continue;
+    };
+    for (name, entry) in dependencies {
+      if entry.get("dev").and_then(Json::as_bool) == Some(true) {
+        continue;
+      }
+      let path = format!("{}node_modules/{}", prefix, name);
+      pending.push((format!("{}/", path), entry));
+      paths.push(path);
+    }
+  }
+  paths
+}
+
+/// One entry of a yarn.lock
+#[derive(Debug, Default)]
+struct YarnEntry {
+  version: String,
+  dependencies: Vec<(String, String)>,
+}
+
+/// Splits a yarn.lock line into its key and value, which yarn 1 separates with a space and later
+/// versions (which write YAML) with a colon
+fn yarn_pair(line: &str) -> Option<(String, String)> {
+  let line = line.trim();
+  let (key, value) = match line.split_once(": ") {
+    Some(pair) => pair,
+    None => match line.strip_suffix(':') {
+      Some(key) => (key, ""),
+      None => line.split_once(' ')?,
+    },
+  };
+  Some((
+    key.trim_matches('"').to_string(),
+    value.trim_matches('"').to_string(),
+  ))
+}
+
+/// Parses a yarn.lock into its entries, keyed by every `name@range` each entry resolves
+fn parse_yarn_lock(text: &str) -> (Vec<YarnEntry>, HashMap<String, usize>) {
+  let mut entries: Vec<YarnEntry> = Vec::new();
+  let mut specs = HashMap::new();
+  let mut in_dependencies = false;
+  for line in text.lines() {
+    if line.trim().is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let indent = line.len() - line.trim_start().len();
+    match indent {
+      0 => {
+        let header = line.trim_end().trim_end_matches(':');
+        for spec in header.split(", ") {
+          specs.insert(spec.trim_matches('"').to_string(), entries.len());
+        }
+        entries.push(YarnEntry::default());
+        in_dependencies = false;
+      }
+      2 => {
+        let (Some(entry), Some((key, value))) = (entries.last_mut(), yarn_pair(line)) else {
+          continue;
+        };
+        in_dependencies = key == "dependencies" || key == "optionalDependencies";
+        if key == "version" {
+          entry.version = value;
+        }
+      }
+      _ if in_dependencies => {
+        if let (Some(entry), Some(dependency)) = (entries.last_mut(), yarn_pair(line)) {
+          entry.dependencies.push(dependency);
+        }
+      }
+      _ => {}
+    }
+  }
+  (entries, specs)
+}
+
+/// The production dependency closure recorded in a yarn.lock, starting from the package.json's
+/// dependencies
+fn yarn_packages(text: &str, manifest: &Json) -> HashSet<PackageId> {
+  let (entries, specs) = parse_yarn_lock(text);
+  let mut packages = HashSet::new();
+  let mut visited = HashSet::new();
+  let mut pending: VecDeque<(String, String)> = json_deps(manifest, &PRODUCTION_SECTIONS)
+    .into_iter()
+    .map(|(name, range)| (name.to_string(), range.to_string()))
+    .collect();
+  while let Some((name, range)) = pending.pop_front() {
+    let spec = format!("{}@{}", name, range);
+    let npm_spec = format!("{}@npm:{}", name, range);
+    let Some(&ix) = specs.get(&spec).or_else(|| specs.get(&npm_spec)) else {
+      continue;
+    };
+    if !visited.insert(ix) {
+      continue;
+    }
+    packages.insert((name, entries[ix].version.clone()));
+    pending.extend(entries[ix].dependencies.iter().cloned());
+  }
+  packages
+}
+
+/// Lists every package installed in the node_modules directory `dir` (at `relative` to the input
+/// path), including nested node_modules, with the name and version from each package.json.
+/// Symlinked packages are listed but not descended into.
+fn installed_packages(dir: &Path, relative: &str, installed: &mut Vec<(String, PackageId)>) {
+  let Ok(entries) = fs::read_dir(dir) else {
+    return;
+  };
+  for entry in entries.filter_map(std::result::Result::ok) {
+    let name = entry.file_name().to_string_lossy().to_string();
+    let path = entry.path();
+    let package_relative = format!("{}/{}", relative, name);
+    if name.starts_with('@') {
+      for scoped in fs::read_dir(&path).into_iter().flatten().flatten() {
+        let scoped_relative = format!(
+          "{}/{}",
+          package_relative,
+          scoped.file_name().to_string_lossy()
+        );
+        installed_package(&scoped.path(), scoped_relative, installed);
+      }
+    } else if !name.starts_with('.') {
+      installed_package(&path, package_relative, installed);
+    }
+  }
+}
+
+fn installed_package(dir: &Path, relative: String, installed: &mut Vec<(String, PackageId)>) {
+  let Ok(manifest) = read_json(&dir.join("package.json")) else {
+    return;
+  };
+  let field = |name| manifest.get(name).and_then(Json::as_str).map(String::from);
+  let (Some(name), Some(version)) = (field("name"), field("version")) else {
+    return;
+  };
+  if !dir.is_symlink() {
+    installed_packages(
+      &dir.join("node_modules"),
+      &format!("{}/node_modules", relative),
+      installed,
+    );
+  }
+  installed.push((relative, (name, version)));
+}
+
+/// Works out which of the packages installed in the input path's node_modules are needed in
+/// production, from its package.json and whichever of package-lock.json (or npm-shrinkwrap.json)
+/// and yarn.lock it has. Returns the package directories relative to the input path, sorted.
+pub fn production_packages(input_path: &Path) -> Result<Vec<String>> {
+  let manifest_path = input_path.join("package.json");
+  let manifest = read_json(&manifest_path)?;
+  let npm_lock = ["npm-shrinkwrap.json", "package-lock.json"]
+    .iter()
+    .map(|name| input_path.join(name))
+    .find(|path| path.is_file());
+  let yarn_lock = input_path.join("yarn.lock");
+  let mut paths = if let Some(path) = npm_lock {
+    npm_packages(&read_json(&path)?)
+      .into_iter()
+      .filter(|path| input_path.join(path).exists())
+      .collect()
+  } else {
+    let packages = if yarn_lock.is_file() {
+      let text = fs::read_to_string(&yarn_lock).map_err(|e| Error::io(&yarn_lock, e))?;
+      yarn_packages(&text, &manifest)
+    } else {
+      return Err(Error::InvalidArgs(format!(
+        "pruning node_modules needs a package-lock.json, yarn.lock or pnpm-lock.yaml in {:?}",
+        input_path
+      )));
+    };
+    let mut installed = Vec::new();
+    installed_packages(
+      &input_path.join("node_modules"),
+      "node_modules",
+      &mut installed,
+    );
+    installed
+      .into_iter()
+      .filter(|(_, id)| packages.contains(id))
+      .map(|(path, _)| path)
+      .collect::<Vec<String>>()
+  };
+  paths.sort();
+  Ok(paths)
+}
+
+/// Include globs that, added after globs matching node_modules, leave out every installed package
+/// that isn't needed in production (see [`production_packages`])
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::node_modules::prune_patterns;
+/// use std::path::Path;
+///
+/// // there's no package.json to work the dependencies out from
+/// assert!(prune_patterns(Path::new("src")).is_err());
+/// ```
+pub fn prune_patterns(input_path: &Path) -> Result<Vec<String>> {
+  let packages = production_packages(input_path)?;
+  let mut patterns = vec!["!node_modules/**".to_string()];
+  for package in packages {
+    let package = globset::escape(&package);
+    // the package itself is matched too, in case it's a symlink that is kept as one
+    patterns.push(format!("{}/**", package));
+    patterns.push(package);
+  }
+  Ok(patterns)
+}
+
+/// What pruning keeps of node_modules: include globs for the packages kept where they're installed,
+/// and packages copied in from wherever they really are
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pruned {
+  /// Include globs to add after globs matching node_modules
+  pub patterns: Vec<String>,
+  /// Packages to place in the archive's node_modules
+  pub packages: Vec<PlacedPackage>,
+}
+
+/// Works out which packages in the input path's node_modules are needed in production. npm and
+/// yarn install real directories, which are kept where they are (see [`prune_patterns`]). pnpm
+/// links each package to its dependencies inside its `.pnpm` store, so the links can only be
+/// followed from the store and the packages are laid out flat instead (see
+/// [`flattened_packages`]). Its pnpm-lock.yaml only marks the project as a pnpm one; the packages
+/// are found by following the package.json dependencies through what's installed. So are the packages of a workspace package, which are installed
+/// under the workspace root (see [`workspace_root`]).
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::node_modules::prune;
+/// use std::path::Path;
+///
+/// // there's no package.json to work the dependencies out from
+/// assert!(prune(Path::new("src")).is_err());
+/// ```
+pub fn prune(input_path: &Path) -> Result<Pruned> {
+  if let Some(root) = workspace_root(input_path) {
+    return Ok(Pruned {
+      patterns: vec!["!node_modules/**".to_string()],
+      packages: flattened_packages(input_path, &root)?,
+    });
+  }
+  let pnpm_only = LOCKFILES
+    .iter()
+    .filter(|name| input_path.join(name).is_file())
+    .eq(&["pnpm-lock.yaml"]);
+  if pnpm_only {
+    return Ok(Pruned {
+      patterns: vec!["!node_modules/**".to_string()],
+      packages: flattened_packages(input_path, input_path)?,
+    });
+  }
+  Ok(Pruned {
+    patterns: prune_patterns(input_path)?,
+    packages: Vec::new(),
+  })
+}
+
+/// The globs a directory lists its workspace packages with, from its pnpm-workspace.yaml or the
+/// `workspaces` in its package.json (a list, or yarn's `{"packages": [...]}`)
+fn workspace_globs(dir: &Path) -> Vec<String> {
+  let globs: Vec<String> = match fs::read_to_string(dir.join("pnpm-workspace.yaml")) {
+    Ok(text) => serde_yaml::from_str::<Yaml>(&text)
+      .ok()
+      .and_then(|workspace| workspace.get("packages")?.as_sequence().cloned())
+      .into_iter()
+      .flatten()
+      .filter_map(|glob| glob.as_str().map(String::from))
+      .collect(),
+    Err(_) => read_json(&dir.join("package.json"))
+      .ok()
+      .and_then(|manifest| {
+        let workspaces = manifest.get("workspaces")?;
+        let globs = workspaces
+          .get("packages")
+          .unwrap_or(workspaces)
+          .as_array()?;
+        Some(
+          globs
+            .iter()
+            .filter_map(Json::as_str)
+            .map(String::from)
+            .collect(),
+        )
+      })
+      .unwrap_or_default(),
+  };
+  globs
+    .into_iter()
+    .map(|glob| glob.trim_end_matches('/').to_string())
+    .collect()
+}
+
+/// Finds the root of the npm, yarn or pnpm workspace that `input_path` is a package of: the
+/// nearest directory above it whose pnpm-workspace.yaml `packages`, or package.json `workspaces`,
+/// match it. Returns `None` if there isn't one, or if `input_path` has a lockfile of its own and
+/// so installs its dependencies into its own node_modules.
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::node_modules::workspace_root;
+/// use std::path::Path;
+///
+/// assert_eq!(workspace_root(Path::new("src")), None);
+/// ```
+pub fn workspace_root(input_path: &Path) -> Option<PathBuf> {
+  if LOCKFILES.iter().any(|name| input_path.join(name).is_file()) {
+    return None;
+  }
+  let input_path = fs::canonicalize(input_path).ok()?;
+  input_path
+    .ancestors()
+    .skip(1)
+    .find(|dir| {
+      let relative = input_path.strip_prefix(dir).unwrap_or(&input_path);
+      PatternList::new(&workspace_globs(dir), false)
+        .is_ok_and(|packages| packages.is_match(relative))
+    })
+    .map(Path::to_path_buf)
+}
+
+/// A package needed in production, and where it goes in the archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlacedPackage {
+  /// The package's real directory, with any symlinks to it followed
+  pub dir: PathBuf,
+  /// Where the package is placed in the archive, like `node_modules/a/node_modules/b`
+  pub path: String,
+}
+
+/// Finds where the dependency `name` of the package in `dir` is installed the way Node does,
+/// looking in the node_modules of each directory above it up to `root`. Symlinks,
+/// like pnpm's into its `.pnpm` store or those to workspace packages, are followed to the real
+/// directory.
+fn resolve_package(dir: &Path, name: &str, root: &Path) -> Option<PathBuf> {
+  for ancestor in dir.ancestors() {
+    if ancestor.file_name() != Some(OsStr::new("node_modules")) {
+      let candidate = ancestor.join("node_modules").join(name);
+      if let Ok(found) = fs::canonicalize(candidate) {
+        return Some(found);
+      }
+    }
+    if ancestor == root {
+      break;
+    }
+  }
+  None
+}
+
+/// The prefixes of the node_modules Node searches for a dependency of the package placed at
+/// `path`, nearest first
+fn placement_scopes(path: &str) -> Vec<String> {
+  let mut scopes = Vec::new();
+  if !path.is_empty() {
+    scopes.push(format!("{}/", path));
+  }
+  scopes.extend(
+    path
+      .rmatch_indices("/node_modules/")
+      .map(|(ix, _)| path[..=ix].to_string()),
+  );
+  scopes.push(String::new());
+  scopes
+}
+
+/// Works out the production dependency closure of the package in `input_path`, resolving each
+/// dependency from wherever it's installed under `root` (the input path itself, or the root of
+/// its workspace), and lays the packages out in a single node_modules tree. Each package is hoisted to the top of the tree
+/// unless a different version of it is already visible from the package that needs it, in which
+/// case it's nested under that package. Dependencies that aren't installed, like optional ones
+/// for other platforms, are skipped. The packages are sorted by where they're placed.
+pub fn flattened_packages(input_path: &Path, root: &Path) -> Result<Vec<PlacedPackage>> {
+  let root = fs::canonicalize(root).map_err(|e| Error::io(root, e))?;
+  let input_path = fs::canonicalize(input_path).map_err(|e| Error::io(input_path, e))?;
+  let mut placed: HashMap<String, PathBuf> = HashMap::new();
+  let mut pending = VecDeque::from([(String::new(), input_path, &PRODUCTION_SECTIONS[..])]);
+  while let Some((path, dir, sections)) = pending.pop_front() {
+    let manifest = read_json(&dir.join("package.json"))?;
+    let scopes = placement_scopes(&path);
+    for (name, _) in json_deps(&manifest, sections) {
+      let Some(dependency) = resolve_package(&dir, name, &root) else {
+        continue;
+      };
+      // a package that (through other versions of its dependencies) ends up needing itself is
+      // already in the archive
+      let needed_by_itself = scopes[..scopes.len() - 1]
+        .iter()
+        .any(|scope| placed.get(scope.trim_end_matches('/')) == Some(&dependency));
+      let visible = scopes.iter().find_map(|scope| {
+        let candidate = format!("{}node_modules/{}", scope, name);
+        placed.get(&candidate)
+      });
+      let target = match visible {
+        Some(visible) if *visible == dependency => continue,
+        _ if needed_by_itself => continue,
+        Some(_) => format!("{}node_modules/{}", scopes[0], name),
+        None => format!("node_modules/{}", name),
+      };
+      placed.insert(target.clone(), dependency.clone());
+      pending.push_back((target, dependency, &RUNTIME_SECTIONS[..]));
+    }
+  }
+  let mut packages: Vec<PlacedPackage> = placed
+    .into_iter()
+    .map(|(path, dir)| PlacedPackage { dir, path })
+    .collect();
+  packages.sort_by(|a, b| a.path.cmp(&b.path));
+  Ok(packages)
+}
+
+/// The files of each placed package, named by where they go in the archive. A package's own
+/// node_modules is left out, since the packages it needs are placed separately.
+pub fn package_sources(
+  packages: &[PlacedPackage],
+  symlinks: SymlinkMode,
+) -> Result<Vec<ZipSource>> {
+  let mut sources = Vec::new();
+  for package in packages {
+    let dir = package.dir.display().to_string();
+    let files = search(
+      &dir,
+      &["**".to_string()],
+      &["node_modules".to_string()],
+      symlinks,
+      false,
+      false,
+    )?;
+    sources.extend(
+      zip::sources(&dir, files)?
+        .into_iter()
+        .map(|source| ZipSource {
+          name: Path::new(&package.path).join(source.name),
+          path: source.path,
+        }),
+    );
+  }
+  Ok(sources)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use tempfile::TempDir;
+
+  use super::*;
+  use crate::testing::temp_dir;
+
+  /// An app depending on `a` (which depends on `b@1` and has its own `c`) and on `b@2`, with a
+  /// dev dependency on `dev`
+  fn installed_app() -> (TempDir, PathBuf) {
+    let (guard, dir) = temp_dir();
+    let packages = [
+      ("node_modules/a", "a", "1.0.0"),
+      ("node_modules/a/node_modules/b", "b", "1.0.0"),
+      ("node_modules/a/node_modules/c", "c", "1.0.0"),
+      ("node_modules/b", "b", "2.0.0"),
+      ("node_modules/@scope/dev", "@scope/dev", "1.0.0"),
+    ];
+    for (path, name, version) in packages {
+      fs::create_dir_all(dir.join(path)).unwrap();
+      fs::write(
+        dir.join(path).join("package.json"),
+        format!(r#"{{"name": "{}", "version": "{}"}}"#, name, version),
+      )
+      .unwrap();
+    }
+    fs::write(
+      dir.join("package.json"),
+      r#"{
+        "dependencies": {"a": "^1.0.0", "b": "^2.0.0"},
+        "devDependencies": {"@scope/dev": "^1.0.0"}
+      }"#,
+    )
+    .unwrap();
+    (guard, dir)
+  }
+
+  const PRODUCTION: [&str; 4] = [
+    "node_modules/a",
+    "node_modules/a/node_modules/b",
+    "node_modules/a/node_modules/c",
+    "node_modules/b",
+  ];
+
+  #[test]
+  fn npm_lockfiles_flag_dev_packages() {
+    let (_guard, dir) = installed_app();
+    fs::write(
+      dir.join("package-lock.json"),
+      r#"{
+        "lockfileVersion": 3,
+        "packages": {
+          "": {"dependencies": {"a": "^1.0.0", "b": "^2.0.0"}},
+          "node_modules/a": {"version": "1.0.0"},
+          "node_modules/a/node_modules/b": {"version": "1.0.0"},
+          "node_modules/a/node_modules/c": {"version": "1.0.0"},
+          "node_modules/b": {"version": "2.0.0"},
+          "node_modules/@scope/dev": {"version": "1.0.0", "dev": true}
+        }
+      }"#,
+    )
+    .unwrap();
+    assert_eq!(production_packages(&dir).unwrap(), PRODUCTION);
+
+    let legacy: Json = serde_json::from_str(
+      r#"{
+        "lockfileVersion": 1,
+        "dependencies": {
+          "a": {"version": "1.0.0", "dependencies": {"b": {"version": "1.0.0"}}},
+          "@scope/dev": {"version": "1.0.0", "dev": true}
+        }
+      }"#,
+    )
+    .unwrap();
+    let mut paths = npm_packages(&legacy);
+    paths.sort();
+    assert_eq!(paths, ["node_modules/a", "node_modules/a/node_modules/b"]);
+  }
+
+  #[test]
+  fn yarn_lockfiles_are_followed_from_the_manifest() {
+    let (_guard, dir) = installed_app();
+    fs::write(
+      dir.join("yarn.lock"),
+      r#"# THIS IS AN AUTOGENERATED FILE. DO NOT EDIT THIS FILE DIRECTLY.
+# yarn lockfile v1
+
+
+"@scope/dev@^1.0.0":
+  version "1.0.0"
+
+a@^1.0.0:
+  version "1.0.0"
+  dependencies:
+    b "^1.0.0"
+    c "~1.0.0"
+
+b@^1.0.0:
+  version "1.0.0"
+
+b@^2.0.0:
+  version "2.0.0"
+
+c@~1.0.0:
+  version "1.0.0"
+"#,
+    )
+    .unwrap();
+    assert_eq!(production_packages(&dir).unwrap(), PRODUCTION);
+
+    let berry = r#"__metadata:
+  version: 6
+
+"a@npm:^1.0.0, a@npm:^1.0.1":
+  version: 1.0.0
+  resolution: "a@npm:1.0.0"
+  dependencies:
+    "@scope/b": "npm:^1.0.0"
+
+"@scope/b@npm:^1.0.0":
+  version: 1.2.0
+  resolution: "@scope/b@npm:1.2.0"
+"#;
+    let manifest = serde_json::json!({"dependencies": {"a": "^1.0.1"}});
+    let packages = yarn_packages(berry, &manifest);
+    assert_eq!(
+      packages,
+      HashSet::from([
+        ("a".to_string(), "1.0.0".to_string()),
+        ("@scope/b".to_string(), "1.2.0".to_string()),
+      ])
+    );
+  }
+
+  #[test]
+  fn pruning_needs_a_lockfile() {
+    let (_guard, dir) = installed_app();
+    let result = production_packages(&dir);
+    assert!(matches!(result, Err(Error::InvalidArgs(_))));
+  }
+
+  /// Writes a package.json into `dir` (relative to `root`), creating it if need be
+  fn write_manifest(root: &Path, dir: &str, manifest: &str) {
+    fs::create_dir_all(root.join(dir)).unwrap();
+    fs::write(root.join(dir).join("package.json"), manifest).unwrap();
+  }
+
+  /// Where each placed package is, relative to the workspace root
+  fn placements(packages: &[PlacedPackage], root: &Path) -> Vec<(String, String)> {
+    packages
+      .iter()
+      .map(|package| {
+        let dir = package.dir.strip_prefix(root).unwrap();
+        (package.path.clone(), dir.display().to_string())
+      })
+      .collect()
+  }
+
+  /// Checks that, from where each package is placed, Node would find the same copy of each of its
+  /// dependencies as it was installed with
+  fn assert_resolvable(input_path: &Path, root: &Path, packages: &[PlacedPackage]) {
+    let placed: HashMap<&str, &PathBuf> = packages
+      .iter()
+      .map(|package| (package.path.as_str(), &package.dir))
+      .collect();
+    let input_path = fs::canonicalize(input_path).unwrap();
+    let needers = packages
+      .iter()
+      .map(|package| (package.path.as_str(), package.dir.clone()))
+      .chain([("", input_path)]);
+    for (path, dir) in needers {
+      let manifest = read_json(&dir.join("package.json")).unwrap();
+      for (name, _) in json_deps(&manifest, &RUNTIME_SECTIONS) {
+        let found = placement_scopes(path).iter().find_map(|scope| {
+          let candidate = format!("{}node_modules/{}", scope, name);
+          placed.get(candidate.as_str()).copied()
+        });
+        let installed = resolve_package(&dir, name, root);
+        assert_eq!(found, installed.as_ref(), "{} from {:?}", name, path);
+      }
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn pnpm_installs_are_laid_out_flat() {
+    use std::os::unix::fs::symlink;
+
+    let (_guard, dir) = temp_dir();
+    write_manifest(
+      &dir,
+      "",
+      r#"{"dependencies": {"a": "^1.0.0"}, "devDependencies": {"dev": "^1.0.0"}}"#,
+    );
+    fs::write(dir.join("pnpm-lock.yaml"), "lockfileVersion: '6.0'\n").unwrap();
+    let store = [
+      ("a@1.0.0", "a", r#"{"dependencies": {"b": "^1.0.0"}}"#),
+      ("b@1.0.0", "b", r#"{"version": "1.0.0"}"#),
+      ("dev@1.0.0", "dev", r#"{"version": "1.0.0"}"#),
+    ];
+    for (id, name, manifest) in store {
+      write_manifest(
+        &dir,
+        &format!("node_modules/.pnpm/{}/node_modules/{}", id, name),
+        manifest,
+      );
+      fs::write(
+        dir.join(format!(
+          "node_modules/.pnpm/{}/node_modules/{}/index.js",
+          id, name
+        )),
+        "",
+      )
+      .unwrap();
+    }
+    symlink(
+      "../../b@1.0.0/node_modules/b",
+      dir.join("node_modules/.pnpm/a@1.0.0/node_modules/b"),
+    )
+    .unwrap();
+    symlink(".pnpm/a@1.0.0/node_modules/a", dir.join("node_modules/a")).unwrap();
+    symlink(
+      ".pnpm/dev@1.0.0/node_modules/dev",
+      dir.join("node_modules/dev"),
+    )
+    .unwrap();
+
+    let pruned = prune(&dir).unwrap();
+    assert_eq!(pruned.patterns, ["!node_modules/**"]);
+    assert_eq!(
+      placements(&pruned.packages, &dir),
+      [
+        (
+          "node_modules/a",
+          "node_modules/.pnpm/a@1.0.0/node_modules/a"
+        ),
+        (
+          "node_modules/b",
+          "node_modules/.pnpm/b@1.0.0/node_modules/b"
+        ),
+      ]
+      .map(|(path, dir)| (path.to_string(), dir.to_string()))
+    );
+    assert_resolvable(&dir, &dir, &pruned.packages);
+    let mut names: Vec<PathBuf> = package_sources(&pruned.packages, SymlinkMode::Follow)
+      .unwrap()
+      .into_iter()
+      .map(|source| source.name)
+      .collect();
+    names.sort();
+    assert_eq!(
+      names,
+      [
+        "node_modules/a/index.js",
+        "node_modules/a/package.json",
+        "node_modules/b/index.js",
+        "node_modules/b/package.json",
+      ]
+      .map(PathBuf::from)
+    );
+  }
+
+  #[test]
+  fn workspace_packages_are_hoisted_from_the_root() {
+    let (_guard, root) = temp_dir();
+    write_manifest(&root, "", r#"{"workspaces": ["packages/*"]}"#);
+    write_manifest(
+      &root,
+      "packages/fn",
+      r#"{
+        "dependencies": {"a": "^1.0.0", "b": "^2.0.0", "c": "^2.0.0"},
+        "devDependencies": {"dev": "^1.0.0"}
+      }"#,
+    );
+    write_manifest(
+      &root,
+      "packages/fn/node_modules/c",
+      r#"{"version": "2.0.0"}"#,
+    );
+    write_manifest(
+      &root,
+      "node_modules/a",
+      r#"{"dependencies": {"b": "^1.0.0", "c": "^1.0.0"}, "optionalDependencies": {"x": "*"}}"#,
+    );
+    write_manifest(
+      &root,
+      "node_modules/a/node_modules/b",
+      r#"{"version": "1.0.0"}"#,
+    );
+    write_manifest(
+      &root,
+      "node_modules/b",
+      r#"{"dependencies": {"a": "^1.0.0"}}"#,
+    );
+    write_manifest(&root, "node_modules/c", r#"{"version": "1.0.0"}"#);
+    write_manifest(&root, "node_modules/dev", r#"{"version": "1.0.0"}"#);
+    fs::write(root.join("node_modules/a/index.js"), "").unwrap();
+
+    let input_path = root.join("packages/fn");
+    assert_eq!(workspace_root(&input_path), Some(root.clone()));
+    let packages = flattened_packages(&input_path, &root).unwrap();
+    assert_eq!(
+      placements(&packages, &root),
+      [
+        ("node_modules/a", "node_modules/a"),
+        (
+          "node_modules/a/node_modules/b",
+          "node_modules/a/node_modules/b"
+        ),
+        ("node_modules/a/node_modules/c", "node_modules/c"),
+        ("node_modules/b", "node_modules/b"),
+        ("node_modules/c", "packages/fn/node_modules/c"),
+      ]
+      .map(|(path, dir)| (path.to_string(), dir.to_string()))
+    );
+    assert_resolvable(&input_path, &root, &packages);
+    let mut names: Vec<PathBuf> = package_sources(&packages[..1], SymlinkMode::Follow)
+      .unwrap()
+      .into_iter()
+      .map(|source| source.name)
+      .collect();
+    names.sort();
+    assert_eq!(
+      names,
+      [
+        PathBuf::from("node_modules/a/index.js"),
+        PathBuf::from("node_modules/a/package.json"),
+      ]
+    );
+
+    // a package the workspace doesn't list isn't part of it
+    write_manifest(
+      &root,
+      "tools/script",
+      r#"{"dependencies": {"a": "^1.0.0"}}"#,
+    );
+    assert_eq!(workspace_root(&root.join("tools/script")), None);
+    write_manifest(
+      &root,
+      "",
+      r#"{"workspaces": {"packages": ["packages/*", "tools/*"]}}"#,
+    );
+    assert_eq!(
+      workspace_root(&root.join("tools/script")),
+      Some(root.clone())
+    );
+
+    // nor is a package with its own lockfile
+    fs::write(input_path.join("package-lock.json"), "{}").unwrap();
+    assert_eq!(workspace_root(&input_path), None);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn pnpm_workspace_links_are_followed_into_the_store() {
+    use std::os::unix::fs::symlink;
+
+    let (_guard, root) = temp_dir();
+    fs::write(
+      root.join("pnpm-workspace.yaml"),
+      "packages:\n  - packages/*\n",
+    )
+    .unwrap();
+    write_manifest(&root, "packages/fn", r#"{"dependencies": {"a": "^1.0.0"}}"#);
+    write_manifest(
+      &root,
+      "node_modules/.pnpm/a@1.0.0/node_modules/a",
+      r#"{"dependencies": {"@scope/b": "^1.0.0"}}"#,
+    );
+    write_manifest(
+      &root,
+      "node_modules/.pnpm/@scope+b@1.0.0/node_modules/@scope/b",
+      r#"{"version": "1.0.0"}"#,
+    );
+    fs::create_dir_all(root.join("node_modules/.pnpm/a@1.0.0/node_modules/@scope")).unwrap();
+    symlink(
+      "../../../@scope+b@1.0.0/node_modules/@scope/b",
+      root.join("node_modules/.pnpm/a@1.0.0/node_modules/@scope/b"),
+    )
+    .unwrap();
+    fs::create_dir_all(root.join("packages/fn/node_modules")).unwrap();
+    symlink(
+      "../../../node_modules/.pnpm/a@1.0.0/node_modules/a",
+      root.join("packages/fn/node_modules/a"),
+    )
+    .unwrap();
+
+    let input_path = root.join("packages/fn");
+    assert_eq!(workspace_root(&input_path), Some(root.clone()));
+    let pruned = prune(&input_path).unwrap();
+    assert_eq!(pruned.patterns, ["!node_modules/**"]);
+    let packages = pruned.packages;
+    assert_eq!(
+      placements(&packages, &root),
+      [
+        (
+          "node_modules/@scope/b",
+          "node_modules/.pnpm/@scope+b@1.0.0/node_modules/@scope/b"
+        ),
+        (
+          "node_modules/a",
+          "node_modules/.pnpm/a@1.0.0/node_modules/a"
+        ),
+      ]
+      .map(|(path, dir)| (path.to_string(), dir.to_string()))
+    );
+    assert_resolvable(&input_path, &root, &packages);
+  }
+}
@@ -1,6 +1,22 @@
-use std::{collections::HashSet, env::current_dir};
+use std::{
+  collections::HashSet,
+  env::current_dir,
+  fs,
+  path::{Component, Path, PathBuf},
+};
 
 use glob::glob;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::{
+  gitignore::{Gitignore, GitignoreBuilder},
+  Match,
+};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::{
+  error::{Error, Result},
+  zip::SymlinkMode,
+};
 
 /// Returns a vector of files that match a glob pattern.
 ///
@@ -9,102 +25,536 @@ use glob::glob;
 /// ```
 /// use push_fn_lib::search::get_files_for_glob;
 ///
-/// let files = get_files_for_glob("src/*");
-/// assert_eq!(files.len(), 8);
+/// let files = get_files_for_glob("src/*").unwrap();
+/// assert_eq!(files.len(), 12);
 /// ```
-pub fn get_files_for_glob(pattern: &str) -> Vec<String> {
-  glob(pattern)
-    .unwrap()
-    .filter_map(Result::ok)
-    .map(|f| f.into_os_string().into_string().unwrap_or_default())
-    .collect::<Vec<String>>()
+pub fn get_files_for_glob(pattern: &str) -> Result<Vec<String>> {
+  let paths = glob(pattern).map_err(|source| Error::Glob {
+    pattern: pattern.to_string(),
+    source: source.into(),
+  })?;
+  Ok(
+    paths
+      .filter_map(std::result::Result::ok)
+      .map(|f| f.into_os_string().into_string().unwrap_or_default())
+      .collect::<Vec<String>>(),
+  )
 }
 
-fn make_patterns_absolute(path: &String, patterns: &[String]) -> Vec<String> {
-  if path == &".".to_string() {
-    return patterns.to_vec();
-  }
-  let base = current_dir().unwrap().join(path);
-  patterns
-    .iter()
-    .map(|pattern| {
-      base
-        .join(pattern)
-        .into_os_string()
-        .into_string()
-        .unwrap_or_default()
+/// An ordered list of gitignore-style globs, matched against paths relative to the search path
+/// with `/` separators on every platform. `*` and `?` never match a `/`, while `**` matches any
+/// number of directories. A pattern starting with `!` negates what earlier patterns matched, so
+/// the last pattern to match a path decides.
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::search::PatternList;
+/// use std::path::Path;
+///
+/// let patterns = PatternList::new(
+///   &[
+///     "node_modules/**/test/**".to_string(),
+///     "!node_modules/foo/test/fixtures.json".to_string(),
+///   ],
+///   false,
+/// )
+/// .unwrap();
+/// assert!(patterns.is_match(Path::new("node_modules/bar/test/index.js")));
+/// assert!(!patterns.is_match(Path::new("node_modules/foo/test/fixtures.json")));
+/// ```
+#[derive(Debug, Clone)]
+pub struct PatternList {
+  set: GlobSet,
+  /// Whether each pattern in the set is negated
+  negated: Vec<bool>,
+  /// Where in the tree each pattern in the set can match
+  scopes: Vec<PatternScope>,
+  ignore_case: bool,
+}
+
+impl Default for PatternList {
+  /// A list without any patterns, which matches nothing
+  fn default() -> Self {
+    PatternList {
+      set: GlobSet::empty(),
+      negated: Vec::new(),
+      scopes: Vec::new(),
+      ignore_case: false,
+    }
+  }
+}
+
+impl PatternList {
+  /// Compiles the patterns in order, matching case-insensitively if `ignore_case` is set
+  pub fn new(patterns: &[String], ignore_case: bool) -> Result<Self> {
+    let mut builder = GlobSetBuilder::new();
+    let mut negated = Vec::with_capacity(patterns.len());
+    let mut scopes = Vec::with_capacity(patterns.len());
+    for pattern in patterns {
+      let (glob, is_negated) = match pattern.strip_prefix('!') {
+        Some(glob) => (glob, true),
+        None => (pattern.as_str(), false),
+      };
+      let glob = normalise_pattern(glob);
+      scopes.push(PatternScope::new(&glob, ignore_case));
+      let glob = GlobBuilder::new(&glob)
+        .literal_separator(true)
+        .case_insensitive(ignore_case)
+        .build()
+        .map_err(|source| Error::Glob {
+          pattern: pattern.clone(),
+          source: source.into(),
+        })?;
+      builder.add(glob);
+      negated.push(is_negated);
+    }
+    let set = builder.build().map_err(|source| Error::Glob {
+      pattern: patterns.join(", "),
+      source: source.into(),
+    })?;
+    Ok(PatternList {
+      set,
+      negated,
+      scopes,
+      ignore_case,
+    })
+  }
+
+  /// Whether the last pattern matching `path` includes it (`Some(true)`) or negates it
+  /// (`Some(false)`), or `None` if no pattern matches
+  pub fn matched(&self, path: &Path) -> Option<bool> {
+    self.matched_name(&path_name(path))
+  }
+
+  /// Like [`PatternList::matched`], but a path no pattern matches takes the decision of its
+  /// nearest matched parent directory, so that matching a directory matches everything in it
+  pub fn matched_path_or_any_parents(&self, path: &Path) -> Option<bool> {
+    let name = path_name(path);
+    let mut end = name.len();
+    loop {
+      if let Some(matched) = self.matched_name(&name[..end]) {
+        return Some(matched);
+      }
+      end = name[..end].rfind('/')?;
+    }
+  }
+
+  /// Whether the last pattern matching `path` includes it
+  pub fn is_match(&self, path: &Path) -> bool {
+    self.matched(path).unwrap_or(false)
+  }
+
+  /// Whether any of the patterns (the negated ones if `negated` is set) could match something
+  /// inside `dir`, judging by the literal directories it starts with and how deep it can reach
+  fn may_match_within(&self, dir: &Path, negated: bool) -> bool {
+    let dir: Vec<String> = path_name(dir)
+      .split('/')
+      .filter(|name| !name.is_empty())
+      .map(|name| match self.ignore_case {
+        true => name.to_lowercase(),
+        false => name.to_string(),
+      })
+      .collect();
+    self
+      .scopes
+      .iter()
+      .zip(&self.negated)
+      .any(|(scope, is_negated)| *is_negated == negated && scope.may_match_within(&dir))
+  }
+
+  /// Whether a pattern (other than a negated one) names the node_modules directory `path` is in
+  /// by its literal directories, like `node_modules/**` does for `node_modules/pkg/index.js`
+  fn names_node_modules_of(&self, path: &Path) -> bool {
+    let names: Vec<String> = path_name(path)
+      .split('/')
+      .map(|name| match self.ignore_case {
+        true => name.to_lowercase(),
+        false => name.to_string(),
+      })
+      .collect();
+    let Some(end) = names.iter().position(|name| name == "node_modules") else {
+      return false;
+    };
+    self
+      .scopes
+      .iter()
+      .zip(&self.negated)
+      .any(|(scope, is_negated)| {
+        !is_negated && scope.prefix.len() > end && scope.prefix[..=end] == names[..=end]
+      })
+  }
+
+  fn matched_name(&self, name: &str) -> Option<bool> {
+    let last = self.set.matches(name).into_iter().max()?;
+    Some(!self.negated[last])
+  }
+}
+
+/// Patterns use `/` as the separator on every platform, and are always relative to the search path
+fn normalise_pattern(pattern: &str) -> String {
+  let pattern = match cfg!(windows) {
+    true => pattern.replace('\\', "/"),
+    false => pattern.to_string(),
+  };
+  let mut pattern = pattern.as_str();
+  while let Some(rest) = pattern.strip_prefix("./") {
+    pattern = rest;
+  }
+  pattern.to_string()
+}
+
+/// The path's components joined by `/`, which is what patterns are matched against
+fn path_name(path: &Path) -> String {
+  path
+    .components()
+    .filter(|component| *component != Component::CurDir)
+    .map(|component| component.as_os_str().to_string_lossy())
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+/// A pattern matching exactly the relative `path`, with any characters patterns treat specially
+/// wrapped in a class of their own
+pub(crate) fn literal_pattern(path: &Path) -> String {
+  path_name(path)
+    .chars()
+    .map(|c| match c {
+      '*' | '?' | '[' | ']' | '{' | '}' | '!' => format!("[{}]", c),
+      c => c.to_string(),
     })
     .collect()
 }
 
-/// Returns a set of files that match the include patterns and do not match the exclude patterns.
+/// The literal directories a pattern starts with, and how many path components deep it can match,
+/// which tell the walker which directories can't hold anything the pattern matches
+#[derive(Debug, Clone)]
+struct PatternScope {
+  prefix: Vec<String>,
+  /// `None` when the pattern has a `**` and so can match at any depth
+  max_depth: Option<usize>,
+}
+
+impl PatternScope {
+  fn new(glob: &str, ignore_case: bool) -> Self {
+    let names: Vec<&str> = glob.split('/').collect();
+    let prefix = names
+      .iter()
+      .take_while(|name| !name.contains(['*', '?', '[', '{', '\\']))
+      .map(|name| match ignore_case {
+        true => name.to_lowercase(),
+        false => name.to_string(),
+      })
+      .collect();
+    let max_depth = match glob.contains("**") {
+      true => None,
+      false => Some(names.len()),
+    };
+    PatternScope { prefix, max_depth }
+  }
+
+  /// Whether something inside the directory whose names are `dir` could match
+  fn may_match_within(&self, dir: &[String]) -> bool {
+    if dir.len() < self.prefix.len() {
+      return dir == &self.prefix[..dir.len()];
+    }
+    dir[..self.prefix.len()] == self.prefix[..] && self.max_depth.is_none_or(|max| dir.len() < max)
+  }
+}
+
+/// The directory the patterns are resolved against, which is left empty when searching the working
+/// directory so that the results stay relative
+fn search_base(path: &String) -> Result<PathBuf> {
+  if path == &".".to_string() {
+    return Ok(PathBuf::new());
+  }
+  canonical_base(path)
+}
+
+/// The input path with `..` and any symlinks resolved, which the files found in it are named
+/// relative to
+pub(crate) fn canonical_base(path: &str) -> Result<PathBuf> {
+  let absolute = current_dir().map_err(|e| Error::io(".", e))?.join(path);
+  fs::canonicalize(&absolute).map_err(|e| Error::io(absolute, e))
+}
+
+/// The ignore files honoured under the input path. When a directory has more than one, the later
+/// ones take precedence.
+pub const IGNORE_FILES: [&str; 3] = [".gitignore", ".npmignore", ".pushfnsignore"];
+
+/// Gitignore-style rules from the ignore files met while walking a search path, with every
+/// pattern relative to the search path
+struct IgnoreRules {
+  /// The rules of each directory being walked that has ignore files, with its depth, outermost
+  /// first
+  dirs: Vec<(usize, Gitignore)>,
+  /// The rules of the project-level `.pushfnsignore`, which have the lowest precedence
+  project: Option<Gitignore>,
+}
+
+impl IgnoreRules {
+  /// Reads the `.pushfnsignore` in `project_dir`, unless that's the search path `root` itself
+  /// (whose ignore files are read as it's walked)
+  fn new(root: &Path, project_dir: &Path) -> Result<Self> {
+    let project_file = project_dir.join(IGNORE_FILES[2]);
+    let same_dir = fs::canonicalize(root).ok() == fs::canonicalize(project_dir).ok();
+    let project = match project_file.is_file() && !same_dir {
+      true => Some(build_matcher(Path::new(""), &[project_file])?),
+      false => None,
+    };
+    Ok(IgnoreRules {
+      dirs: Vec::new(),
+      project,
+    })
+  }
+
+  /// Forgets the rules of directories the walk has left, given the depth it has reached
+  fn leave(&mut self, depth: usize) {
+    while self
+      .dirs
+      .last()
+      .is_some_and(|(dir_depth, _)| *dir_depth >= depth)
+    {
+      self.dirs.pop();
+    }
+  }
+
+  /// Reads the ignore files in `dir` (at `relative` to the search path) as the walk enters it,
+  /// skipping any inside `node_modules` since those belong to installed packages rather than the
+  /// project
+  fn enter(&mut self, dir: &Path, relative: &Path, depth: usize) -> Result<()> {
+    let in_node_modules = relative
+      .components()
+      .any(|c| c == Component::Normal("node_modules".as_ref()));
+    if in_node_modules {
+      return Ok(());
+    }
+    let files: Vec<PathBuf> = IGNORE_FILES
+      .iter()
+      .map(|name| dir.join(name))
+      .filter(|file| file.is_file())
+      .collect();
+    if !files.is_empty() {
+      self.dirs.push((depth, build_matcher(relative, &files)?));
+    }
+    Ok(())
+  }
+
+  /// Whether `relative` is ignored, which the deepest ignore file with a matching pattern decides.
+  /// Ignored directories aren't walked, so the paths inside them never need checking.
+  fn is_ignored(&self, relative: &Path, is_dir: bool) -> bool {
+    let matchers = self.dirs.iter().rev().map(|(_, matcher)| matcher);
+    for matcher in matchers.chain(&self.project) {
+      match matcher.matched(relative, is_dir) {
+        Match::Ignore(_) => return true,
+        Match::Whitelist(_) => return false,
+        Match::None => {}
+      }
+    }
+    false
+  }
+}
+
+/// Builds a matcher for the ignore `files`, with their patterns relative to `dir`
+fn build_matcher(dir: &Path, files: &[PathBuf]) -> Result<Gitignore> {
+  let mut builder = GitignoreBuilder::new(dir);
+  for file in files {
+    if let Some(source) = builder.add(file) {
+      return Err(Error::Ignore {
+        path: file.clone(),
+        source,
+      });
+    }
+  }
+  builder.build().map_err(|source| Error::Ignore {
+    path: dir.to_path_buf(),
+    source,
+  })
+}
+
+/// Whether a walked entry belongs in an archive. Regular files do, while directories, sockets,
+/// FIFOs and devices don't. Symlinks are only walked through when following them, otherwise they're
+/// kept as links or rejected depending on `symlinks`.
+fn is_archivable(entry: &DirEntry, symlinks: SymlinkMode) -> Result<bool> {
+  match symlinks {
+    SymlinkMode::Reject if entry.path_is_symlink() => Err(Error::InvalidArgs(format!(
+      "{:?} is a symlink; pass --symlinks follow or --symlinks preserve to include it",
+      entry.path()
+    ))),
+    SymlinkMode::Preserve if entry.path_is_symlink() => Ok(true),
+    _ => Ok(entry.file_type().is_file()),
+  }
+}
+
+/// Returns the set of files that match the include patterns and do not match the exclude patterns.
+/// Both are ordered [`PatternList`]s, so a later `!pattern` takes back what an earlier pattern
+/// matched, and excluding a directory excludes everything in it. Only files are returned, never
+/// the directories the globs match, and symlinks are handled according to `symlinks`. With
+/// `ignore_files`, files matched by the [`IGNORE_FILES`] under `path` (or by a `.pushfnsignore` in
+/// the working directory) are skipped too, following gitignore rules, except in a node_modules
+/// directory an include pattern names. With `ignore_case`, the patterns match regardless of case.
+///
+/// The tree is walked once, skipping directories that no include pattern reaches and those that
+/// are excluded or ignored.
 ///
 /// # Examples
 ///
 /// ```
-/// use push_fn_lib::search::search;
+/// use push_fn_lib::{search::search, zip::SymlinkMode};
 ///
 /// let files = search(
 ///     &".".to_string(),
 ///     &["src/*".to_string(), ".devcontainer/*".to_string()],
 ///     &[],
-/// );
+///     SymlinkMode::Follow,
+///     false,
+///     false,
+/// )
+/// .unwrap();
 /// println!("files: {:#?}", files);
-/// assert_eq!(files.len(), 9);
+/// assert_eq!(files.len(), 12);
 /// ```
-pub fn search(path: &String, include: &[String], exclude: &[String]) -> HashSet<String> {
-  let all_excluded_files: HashSet<String> = make_patterns_absolute(path, exclude)
-    .iter()
-    .flat_map(|p| get_files_for_glob(p))
-    .collect();
+pub fn search(
+  path: &String,
+  include: &[String],
+  exclude: &[String],
+  symlinks: SymlinkMode,
+  ignore_files: bool,
+  ignore_case: bool,
+) -> Result<HashSet<String>> {
+  let include_patterns = PatternList::new(include, ignore_case)?;
+  let exclude_patterns = PatternList::new(exclude, ignore_case)?;
+  let base = search_base(path)?;
+  let root = match base.as_os_str().is_empty() {
+    true => Path::new("."),
+    false => base.as_path(),
+  };
+  let mut ignore_rules = match ignore_files {
+    true => Some(IgnoreRules::new(
+      root,
+      &current_dir().map_err(|e| Error::io(".", e))?,
+    )?),
+    false => None,
+  };
 
-  let all_incuded_files: HashSet<String> = make_patterns_absolute(path, include)
-    .iter()
-    .flat_map(|p| get_files_for_glob(p))
-    .filter(|f| !all_excluded_files.contains(f))
-    .collect();
+  let mut files = HashSet::new();
+  let mut walk = WalkDir::new(root)
+    .follow_links(symlinks == SymlinkMode::Follow)
+    .into_iter();
+  while let Some(entry) = walk.next() {
+    let entry = entry.map_err(|e| {
+      let path = e.path().unwrap_or(root).to_path_buf();
+      Error::io(path, e.into())
+    })?;
+    let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+    let is_dir = entry.file_type().is_dir();
+    if let Some(rules) = ignore_rules.as_mut() {
+      rules.leave(entry.depth());
+    }
+    if entry.depth() == 0 {
+      if let Some(rules) = ignore_rules.as_mut() {
+        rules.enter(entry.path(), relative, 0)?;
+      }
+      continue;
+    }
 
-  all_incuded_files
+    // a link to a directory isn't walked into unless following links, so it stands in for
+    // anything the include patterns could match inside it
+    let linked_dir =
+      symlinks != SymlinkMode::Follow && entry.path_is_symlink() && entry.path().is_dir();
+    let excluded = exclude_patterns.matched_path_or_any_parents(relative) == Some(true);
+    // ignore files usually list node_modules, which shouldn't stop it being archived (in a layer,
+    // say) when an include pattern asks for it by name
+    let ignored = ignore_rules.as_ref().is_some_and(|rules| {
+      rules.is_ignored(relative, is_dir || linked_dir)
+        && !include_patterns.names_node_modules_of(relative)
+    });
+    if is_dir {
+      let pruned = ignored
+        || !include_patterns.may_match_within(relative, false)
+        || (excluded && !exclude_patterns.may_match_within(relative, true));
+      match (pruned, ignore_rules.as_mut()) {
+        (true, _) => walk.skip_current_dir(),
+        (false, Some(rules)) => rules.enter(entry.path(), relative, entry.depth())?,
+        (false, None) => {}
+      }
+      continue;
+    }
+    let included = include_patterns.matched(relative) == Some(true)
+      || (linked_dir && include_patterns.may_match_within(relative, false));
+    if included && !excluded && !ignored && is_archivable(&entry, symlinks)? {
+      let file = base.join(relative);
+      files.insert(file.into_os_string().into_string().unwrap_or_default());
+    }
+  }
+  Ok(files)
 }
 
 #[cfg(test)]
 mod tests {
   use std::env;
 
+  use tempfile::TempDir;
+
   use super::*;
+  use crate::testing::temp_dir;
 
-  const NUM_FILES: usize = 7;
+  // the files directly under src, which also holds one directory
+  const NUM_FILES: usize = 11;
 
   #[test]
-  fn test_absolute_patterns() {
-    let patterns = make_patterns_absolute(&"src".to_string(), &["search*".to_string()]);
-    let here = current_dir().unwrap();
-    let expected = vec![here
-      .join("src")
-      .join("search*")
-      .into_os_string()
-      .into_string()
-      .unwrap()];
-    println!("patterns: {:#?}", patterns);
-    assert_eq!(expected, patterns);
+  fn patterns_know_which_directories_they_can_reach() {
+    let patterns = PatternList::new(
+      &[
+        "src/*".to_string(),
+        "node_modules/**".to_string(),
+        "!Docs/*.md".to_string(),
+      ],
+      true,
+    )
+    .unwrap();
+    let reaches = |dir: &str, negated| patterns.may_match_within(Path::new(dir), negated);
+    assert!(reaches("src", false));
+    assert!(!reaches("src/upload", false));
+    assert!(reaches("node_modules/foo/lib", false));
+    assert!(!reaches("target", false));
+    assert!(!reaches("docs", false));
+    assert!(reaches("docs", true));
+    assert!(!reaches("docs/api", true));
   }
 
   #[test]
   fn test_get_files_for_glob() {
-    let files = get_files_for_glob("src/*");
+    let files = get_files_for_glob("src/*").unwrap();
     assert_eq!(files.len(), NUM_FILES + 1);
   }
 
   #[test]
   fn search_works_with_different_paths() {
-    let files = search(&"src".to_string(), &["*".to_string()], &[]);
-    assert_eq!(files.len(), NUM_FILES + 1);
+    let files = search(
+      &"src".to_string(),
+      &["*".to_string()],
+      &[],
+      SymlinkMode::Follow,
+      false,
+      false,
+    )
+    .unwrap();
+    assert_eq!(files.len(), NUM_FILES);
     let include = match env::consts::OS {
       "windows" => "src\\*",
       _ => "src/*",
     };
-    let files = search(&".".to_string(), &[include.to_string()], &[]);
-    assert_eq!(files.len(), NUM_FILES + 1);
+    let files = search(
+      &".".to_string(),
+      &[include.to_string()],
+      &[],
+      SymlinkMode::Follow,
+      false,
+      false,
+    )
+    .unwrap();
+    assert_eq!(files.len(), NUM_FILES);
   }
 
   #[test]
@@ -113,8 +563,12 @@ mod tests {
       &"src".to_string(),
       &["*".to_string()],
       &["search*".to_string()],
-    );
-    assert_eq!(files.len(), NUM_FILES);
+      SymlinkMode::Follow,
+      false,
+      false,
+    )
+    .unwrap();
+    assert_eq!(files.len(), NUM_FILES - 1);
   }
 
   #[test]
@@ -123,8 +577,12 @@ mod tests {
       &"src".to_string(),
       &["*".to_string()],
       &["search*".to_string(), "zip*".to_string()],
-    );
-    assert_eq!(files.len(), NUM_FILES - 1);
+      SymlinkMode::Follow,
+      false,
+      false,
+    )
+    .unwrap();
+    assert_eq!(files.len(), NUM_FILES - 2);
   }
 
   #[test]
@@ -133,8 +591,210 @@ mod tests {
       &".".to_string(),
       &["src/*".to_string(), ".devcontainer/*".to_string()],
       &[],
-    );
+      SymlinkMode::Follow,
+      false,
+      false,
+    )
+    .unwrap();
     println!("files: {:#?}", files);
-    assert_eq!(files.len(), NUM_FILES + 2);
+    assert_eq!(files.len(), NUM_FILES + 1);
+  }
+
+  #[test]
+  fn later_patterns_can_negate_earlier_ones() {
+    let (_guard, dir) = temp_dir();
+    for name in [
+      "index.js",
+      "index.test.js",
+      "README.md",
+      "docs/guide.md",
+      "node_modules/foo/index.js",
+      "node_modules/foo/test/fixtures.json",
+      "node_modules/foo/test/index.js",
+      "node_modules/bar/test/index.js",
+    ] {
+      let path = dir.join(name);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, "").unwrap();
+    }
+    let path = dir.to_str().unwrap().to_string();
+    let include = ["**".to_string(), "!*.test.js".to_string()];
+    let exclude = [
+      "docs".to_string(),
+      "node_modules/**/test/**".to_string(),
+      "!node_modules/foo/test/fixtures.json".to_string(),
+      "*.MD".to_string(),
+    ];
+    let mut files: Vec<String> =
+      search(&path, &include, &exclude, SymlinkMode::Follow, false, false)
+        .unwrap()
+        .iter()
+        .map(|f| path_name(Path::new(f).strip_prefix(&dir).unwrap()))
+        .collect();
+    files.sort();
+    assert_eq!(
+      files,
+      [
+        "README.md",
+        "index.js",
+        "node_modules/foo/index.js",
+        "node_modules/foo/test/fixtures.json",
+      ]
+    );
+
+    let files = search(&path, &include, &exclude, SymlinkMode::Follow, false, true).unwrap();
+    assert!(!files.iter().any(|f| f.ends_with("README.md")));
+    assert_eq!(files.len(), 3);
+  }
+
+  #[test]
+  fn search_reports_invalid_globs() {
+    let result = search(
+      &".".to_string(),
+      &["src/[".to_string()],
+      &[],
+      SymlinkMode::Follow,
+      false,
+      false,
+    );
+    assert!(matches!(result, Err(Error::Glob { .. })));
+  }
+
+  #[cfg(unix)]
+  fn symlinked_tree() -> (TempDir, PathBuf) {
+    use std::os::unix::{fs::symlink, net::UnixListener};
+
+    let (guard, dir) = temp_dir();
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(dir.join("lib").join("index.js"), "").unwrap();
+    fs::write(dir.join("main.js"), "").unwrap();
+    symlink("main.js", dir.join("entry.js")).unwrap();
+    symlink("lib", dir.join("linked")).unwrap();
+    UnixListener::bind(dir.join("app.sock")).unwrap();
+    (guard, dir)
+  }
+
+  #[cfg(unix)]
+  fn names(files: HashSet<String>, dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = files
+      .iter()
+      .map(|f| {
+        let path = Path::new(f).strip_prefix(dir).unwrap();
+        path.to_str().unwrap().to_string()
+      })
+      .collect();
+    names.sort();
+    names
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn only_files_and_symlinks_are_returned() {
+    let (_guard, dir) = symlinked_tree();
+    let path = dir.to_str().unwrap().to_string();
+    let include = ["**".to_string()];
+
+    let followed = search(&path, &include, &[], SymlinkMode::Follow, false, false).unwrap();
+    assert_eq!(
+      names(followed, &dir),
+      ["entry.js", "lib/index.js", "linked/index.js", "main.js"]
+    );
+
+    let preserved = search(&path, &include, &[], SymlinkMode::Preserve, false, false).unwrap();
+    assert_eq!(
+      names(preserved, &dir),
+      ["entry.js", "lib/index.js", "linked", "main.js"]
+    );
+
+    let rejected = search(&path, &include, &[], SymlinkMode::Reject, false, false);
+    assert!(matches!(rejected, Err(Error::InvalidArgs(_))));
+
+    // a linked directory is kept or rejected when the patterns reach inside it
+    let inside = ["linked/**".to_string()];
+    let preserved = search(&path, &inside, &[], SymlinkMode::Preserve, false, false).unwrap();
+    assert_eq!(names(preserved, &dir), ["linked"]);
+    let rejected = search(&path, &inside, &[], SymlinkMode::Reject, false, false);
+    assert!(matches!(rejected, Err(Error::InvalidArgs(_))));
+    let excluded = search(
+      &path,
+      &include,
+      &[
+        "entry.js".to_string(),
+        "linked".to_string(),
+        "linked/**".to_string(),
+      ],
+      SymlinkMode::Reject,
+      false,
+      false,
+    )
+    .unwrap();
+    assert_eq!(names(excluded, &dir), ["lib/index.js", "main.js"]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn ignore_files_are_honoured() {
+    let (_guard, dir) = temp_dir();
+    let files = [
+      ("index.js", ""),
+      ("app.log", ""),
+      ("keep.log", ""),
+      ("README.md", ""),
+      ("secret.txt", ""),
+      ("build/out.js", ""),
+      ("dist/a.js", ""),
+      ("sub/build/x.js", ""),
+      ("sub/dist/b.js", ""),
+      ("sub/secret.txt", ""),
+      ("node_modules/pkg/index.js", ""),
+      (
+        ".gitignore",
+        "*.log\n!keep.log\n/build\ndist/\n!README.md\n",
+      ),
+      (".pushfnsignore", "*.md\n"),
+      ("sub/.npmignore", "secret.txt\n"),
+      ("node_modules/pkg/.npmignore", "index.js\n"),
+    ];
+    for (name, contents) in files {
+      let path = dir.join(name);
+      fs::create_dir_all(path.parent().unwrap()).unwrap();
+      fs::write(path, contents).unwrap();
+    }
+    let path = dir.to_str().unwrap().to_string();
+    let include = ["**".to_string()];
+    let exclude = ["**/.*".to_string()];
+
+    let all = search(&path, &include, &exclude, SymlinkMode::Follow, false, false).unwrap();
+    assert_eq!(all.len(), 11);
+    let kept = search(&path, &include, &exclude, SymlinkMode::Follow, true, false).unwrap();
+    assert_eq!(
+      names(kept, &dir),
+      [
+        "index.js",
+        "keep.log",
+        "node_modules/pkg/index.js",
+        "secret.txt",
+        "sub/build/x.js",
+      ]
+    );
+
+    let project = dir.join("project");
+    fs::create_dir_all(&project).unwrap();
+    fs::write(project.join(".pushfnsignore"), "/secret.txt\n").unwrap();
+    let rules = IgnoreRules::new(&dir, &project).unwrap();
+    assert!(rules.is_ignored(Path::new("secret.txt"), false));
+    assert!(!rules.is_ignored(Path::new("index.js"), false));
+
+    // node_modules is ignored, unless it's asked for by name
+    fs::write(dir.join(".gitignore"), "node_modules\n").unwrap();
+    let kept = search(&path, &include, &exclude, SymlinkMode::Follow, true, false).unwrap();
+    assert!(!names(kept, &dir).contains(&"node_modules/pkg/index.js".to_string()));
+    let layer = ["node_modules/**".to_string()];
+    let kept = search(&path, &layer, &exclude, SymlinkMode::Follow, true, false).unwrap();
+    assert_eq!(names(kept, &dir), ["node_modules/pkg/index.js"]);
+
+    fs::write(dir.join(".gitignore"), "{a,b\n").unwrap();
+    let invalid = search(&path, &include, &exclude, SymlinkMode::Follow, true, false);
+    assert!(matches!(invalid, Err(Error::Ignore { .. })));
   }
 }
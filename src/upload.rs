@@ -3,3 +3,153 @@ pub mod aws_s3;
 
 /// The Google Cloud Storage upload module
 pub mod google_cloud_storage;
+
+/// The retry policy shared by the upload backends
+pub mod retry;
+
+use std::{fmt, future::Future};
+
+use futures::stream::{self, StreamExt};
+
+use crate::error::Result;
+
+/// The number of bytes in a mebibyte, which part and chunk sizes are given in on the command line
+pub const MIB: u64 = 1024 * 1024;
+
+/// What happened to an artifact at a destination that didn't fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadStatus {
+  /// The artifact was uploaded
+  Uploaded,
+  /// The remote object already had identical content, so the upload was skipped
+  Unchanged,
+}
+
+impl fmt::Display for UploadStatus {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      UploadStatus::Uploaded => write!(f, "uploaded"),
+      UploadStatus::Unchanged => write!(f, "unchanged"),
+    }
+  }
+}
+
+/// The outcome of uploading one artifact to one destination
+#[derive(Debug)]
+pub struct UploadReport {
+  /// Where the artifact was sent, e.g. `s3://bucket/key.zip (eu-west-2)`
+  pub destination: String,
+  /// Whether the upload succeeded, and if so whether anything needed sending
+  pub result: Result<UploadStatus>,
+}
+
+/// Runs the uploads with at most `concurrency` of them in flight at once, returning a report for
+/// each one in the order they were given. A failed upload doesn't stop the others.
+pub async fn run_uploads<I, F>(uploads: I, concurrency: usize) -> Vec<UploadReport>
+where
+  I: IntoIterator<Item = F>,
+  F: Future<Output = UploadReport>,
+{
+  stream::iter(uploads)
+    .buffered(concurrency.max(1))
+    .collect()
+    .await
+}
+
+/// Formats the reports as a table with one row per destination
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::upload::{summary_table, UploadReport, UploadStatus};
+///
+/// let table = summary_table(&[UploadReport {
+///   destination: "s3://bucket/fn.zip (eu-west-2)".to_string(),
+///   result: Ok(UploadStatus::Uploaded),
+/// }]);
+/// assert!(table.contains("s3://bucket/fn.zip (eu-west-2)  uploaded"));
+/// ```
+pub fn summary_table(reports: &[UploadReport]) -> String {
+  let heading = "DESTINATION";
+  let width = reports
+    .iter()
+    .map(|r| r.destination.len())
+    .chain([heading.len()])
+    .max()
+    .unwrap_or_default();
+  let mut table = format!("{:width$}  STATUS\n", heading, width = width);
+  for report in reports {
+    let status = match &report.result {
+      Ok(status) => status.to_string(),
+      Err(e) => format!("failed: {}", e),
+    };
+    table.push_str(&format!(
+      "{:width$}  {}\n",
+      report.destination,
+      status,
+      width = width
+    ));
+  }
+  table
+}
+
+/// Prints the summary table for a set of uploads, then returns the first failure if there was one
+pub fn finish_uploads(reports: Vec<UploadReport>) -> Result<()> {
+  print!("{}", summary_table(&reports));
+  match reports.into_iter().find_map(|r| r.result.err()) {
+    Some(e) => Err(e),
+    None => Ok(()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use tokio::time::sleep;
+
+  use super::*;
+  use crate::error::Error;
+
+  fn report(destination: &str, result: Result<UploadStatus>) -> UploadReport {
+    UploadReport {
+      destination: destination.to_string(),
+      result,
+    }
+  }
+
+  #[tokio::test]
+  async fn uploads_keep_their_order_and_all_run() {
+    let uploads = (0..5u64).map(|ix| async move {
+      sleep(Duration::from_millis(50 - ix * 10)).await;
+      let result = match ix {
+        1 => Err(Error::Aws("boom".to_string())),
+        _ => Ok(UploadStatus::Uploaded),
+      };
+      report(&ix.to_string(), result)
+    });
+    let reports = run_uploads(uploads, 2).await;
+    let destinations: Vec<&str> = reports.iter().map(|r| r.destination.as_str()).collect();
+    assert_eq!(destinations, ["0", "1", "2", "3", "4"]);
+    assert_eq!(reports.iter().filter(|r| r.result.is_err()).count(), 1);
+  }
+
+  #[test]
+  fn summary_lists_every_destination() {
+    let reports = vec![
+      report("s3://a/fn.zip (eu-west-2)", Ok(UploadStatus::Uploaded)),
+      report("s3://c/fn.zip (eu-west-1)", Ok(UploadStatus::Unchanged)),
+      report(
+        "s3://b/fn.zip (us-east-1)",
+        Err(Error::Auth("expired".to_string())),
+      ),
+    ];
+    let table = summary_table(&reports);
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[1].ends_with("uploaded"));
+    assert!(lines[2].ends_with("unchanged"));
+    assert!(lines[3].ends_with("failed: authentication failed: expired"));
+    assert!(matches!(finish_uploads(reports), Err(Error::Auth(_))));
+  }
+}
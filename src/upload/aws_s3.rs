@@ -1,42 +1,385 @@
+use std::{collections::HashMap, error::Error as _, ops::Range};
+
+use aws_config::SdkConfig;
+use aws_credential_types::provider::error::CredentialsError;
+use aws_sdk_s3::config::retry::RetryConfig;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
 use aws_sdk_s3::primitives::ByteStream;
-use aws_sdk_s3::{config::Region, Client};
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::{config, config::Region, Client};
+use aws_sig_auth::middleware::SigningStageError;
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+use crate::{
+  artifact::Artifact,
+  error::{Error, Result},
+  upload::{retry::RetryPolicy, UploadStatus},
+};
+
+/// The object metadata key we store the archive's SHA-256 under, so later pushes can tell whether
+/// the remote object already has the same content
+pub const SHA256_METADATA_KEY: &str = "sha256";
+
+/// S3 won't accept a multipart upload with more parts than this
+const MAX_PARTS: u64 = 10_000;
+
+/// S3 won't accept parts (other than the last) smaller than this
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+/// How an artifact is sent to S3
+#[derive(Debug, Clone)]
+pub struct S3UploadOptions {
+  /// Upload even if the remote object already has identical content
+  pub force: bool,
+  /// Artifacts larger than this many bytes are sent as a multipart upload
+  pub multipart_threshold: u64,
+  /// The size in bytes of each part of a multipart upload
+  pub part_size: u64,
+  /// The maximum number of parts of one upload to send at once
+  pub part_concurrency: usize,
+  /// How each request is retried after a transient failure
+  pub retry: RetryPolicy,
+}
+
+impl Default for S3UploadOptions {
+  fn default() -> Self {
+    S3UploadOptions {
+      force: false,
+      multipart_threshold: 16 * 1024 * 1024,
+      part_size: 8 * 1024 * 1024,
+      part_concurrency: 4,
+      retry: RetryPolicy::default(),
+    }
+  }
+}
+
+/// S3 error codes which mean the credentials are missing, expired or not allowed to write
+const AUTH_ERROR_CODES: [&str; 6] = [
+  "AccessDenied",
+  "ExpiredToken",
+  "InvalidAccessKeyId",
+  "InvalidToken",
+  "SignatureDoesNotMatch",
+  "TokenRefreshRequired",
+];
+
+/// S3 error codes which mean the request was throttled or S3 had a temporary problem
+const RETRYABLE_ERROR_CODES: [&str; 5] = [
+  "InternalError",
+  "RequestTimeout",
+  "ServiceUnavailable",
+  "SlowDown",
+  "Throttling",
+];
+
+/// Whether a failed S3 request is worth trying again: timeouts, dropped connections, throttling
+/// and server errors are, while anything the caller got wrong (403, NoSuchBucket, ...) is not
+pub(crate) fn is_retryable<E>(err: &SdkError<E>) -> bool
+where
+  E: ProvideErrorMetadata,
+{
+  match err {
+    SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => true,
+    SdkError::ServiceError(e) => {
+      let status = e.raw().http().status();
+      RETRYABLE_ERROR_CODES.contains(&e.err().code().unwrap_or_default())
+        || status.as_u16() == 429
+        || status.is_server_error()
+    }
+    _ => false,
+  }
+}
+
+/// Maps an S3 SDK error onto our error type, separating credential problems from other failures
+pub(crate) fn map_sdk_error<E, R>(err: SdkError<E, R>) -> Error
+where
+  E: ProvideErrorMetadata + std::error::Error + 'static,
+  R: std::fmt::Debug,
+{
+  let message = DisplayErrorContext(&err).to_string();
+  match &err {
+    // credentials are resolved and the request signed before anything is sent
+    SdkError::ConstructionFailure(_) if err.source().is_some_and(is_credentials_error) => {
+      Error::Auth(message)
+    }
+    SdkError::ServiceError(e) if AUTH_ERROR_CODES.contains(&e.err().code().unwrap_or_default()) => {
+      Error::Auth(message)
+    }
+    _ => Error::Aws(message),
+  }
+}
+
+/// Whether an error, or any error behind it, is about credentials that couldn't be loaded or a
+/// request that couldn't be signed with them
+fn is_credentials_error(err: &(dyn std::error::Error + 'static)) -> bool {
+  let mut source = Some(err);
+  while let Some(err) = source {
+    if err.is::<CredentialsError>() || err.is::<SigningStageError>() {
+      return true;
+    }
+    source = err.source();
+  }
+  false
+}
+
+/// Creates one S3 client for each distinct region from the shared AWS config, which only needs
+/// loading once however many clients are made. The SDK's own retries are turned off since every
+/// request is retried by our [`RetryPolicy`].
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::upload::aws_s3::s3_clients;
+///
+/// async fn do_something() {
+///     let shared_config = aws_config::load_from_env().await;
+///     let clients = s3_clients(&shared_config, &["eu-west-2".to_string(), "eu-west-1".to_string()]);
+///     assert_eq!(clients.len(), 2);
+/// }
+/// ```
+pub fn s3_clients(shared_config: &SdkConfig, regions: &[String]) -> HashMap<String, Client> {
+  regions
+    .iter()
+    .map(|region| {
+      let config = config::Builder::from(shared_config)
+        .region(Region::new(region.to_owned()))
+        .retry_config(RetryConfig::disabled())
+        .build();
+      (region.to_owned(), Client::from_conf(config))
+    })
+    .collect()
+}
+
+/// The region a bucket lives in, given its location constraint. Buckets in us-east-1 have no
+/// constraint, and some old buckets in eu-west-1 still report the legacy `EU`.
+fn constraint_region(constraint: Option<&str>) -> String {
+  match constraint {
+    None | Some("") => "us-east-1".to_string(),
+    Some("EU") => "eu-west-1".to_string(),
+    Some(region) => region.to_string(),
+  }
+}
+
+/// Looks up the region a bucket lives in. Any client can ask, whichever region it is set up for.
+pub async fn bucket_region(client: &Client, bucket: &str, retry: &RetryPolicy) -> Result<String> {
+  let location = retry
+    .run(
+      || client.get_bucket_location().bucket(bucket).send(),
+      is_retryable,
+    )
+    .await
+    .map_err(map_sdk_error)?;
+  Ok(constraint_region(
+    location
+      .location_constraint()
+      .map(|constraint| constraint.as_str()),
+  ))
+}
+
+/// Checks whether the object at `key` was uploaded by us with the same content hash. Any failure
+/// to read the object's metadata just means we can't prove it is unchanged.
+async fn is_unchanged(client: &Client, bucket: &String, artifact: &Artifact) -> bool {
+  let head = client
+    .head_object()
+    .bucket(bucket)
+    .key(&artifact.key)
+    .send()
+    .await;
+  match head {
+    Ok(object) => object
+      .metadata()
+      .and_then(|metadata| metadata.get(SHA256_METADATA_KEY))
+      .is_some_and(|sha256| sha256 == &artifact.sha256),
+    Err(_) => false,
+  }
+}
+
+/// Splits `len` bytes into the byte ranges of each part, growing the part size if needed to stay
+/// within the maximum number of parts S3 allows
+fn part_ranges(len: u64, part_size: u64) -> Vec<Range<u64>> {
+  let part_size = part_size.max(MIN_PART_SIZE).max(len.div_ceil(MAX_PARTS));
+  (0..len)
+    .step_by(part_size as usize)
+    .map(|start| start..(start + part_size).min(len))
+    .collect()
+}
+
+/// Sends every part of a multipart upload, a few at a time, failing on the first part that fails
+async fn upload_parts(
+  client: &Client,
+  bucket: &String,
+  artifact: &Artifact,
+  upload_id: &str,
+  options: &S3UploadOptions,
+) -> Result<Vec<CompletedPart>> {
+  let parts = part_ranges(artifact.data.size(), options.part_size)
+    .into_iter()
+    .enumerate()
+    .map(|(ix, range)| async move {
+      let part_number = ix as i32 + 1;
+      let body = artifact.data.read_range(range)?;
+      let output = options
+        .retry
+        .run(
+          || {
+            client
+              .upload_part()
+              .bucket(bucket)
+              .key(&artifact.key)
+              .upload_id(upload_id)
+              .part_number(part_number)
+              .body(ByteStream::from(body.clone()))
+              .send()
+          },
+          is_retryable,
+        )
+        .await
+        .map_err(map_sdk_error)?;
+      let mut part = CompletedPart::builder().part_number(part_number);
+      if let Some(e_tag) = output.e_tag() {
+        part = part.e_tag(e_tag);
+      }
+      Ok(part.build())
+    });
+  stream::iter(parts)
+    .buffered(options.part_concurrency.max(1))
+    .try_collect()
+    .await
+}
+
+/// Uploads an artifact in parts, aborting the upload if any part fails so S3 doesn't keep (and
+/// charge for) the parts that did make it
+async fn multipart_upload(
+  client: &Client,
+  bucket: &String,
+  artifact: &Artifact,
+  options: &S3UploadOptions,
+) -> Result<()> {
+  let created = options
+    .retry
+    .run(
+      || {
+        client
+          .create_multipart_upload()
+          .bucket(bucket)
+          .key(&artifact.key)
+          .metadata(SHA256_METADATA_KEY, &artifact.sha256)
+          .send()
+      },
+      is_retryable,
+    )
+    .await
+    .map_err(map_sdk_error)?;
+  let upload_id = created
+    .upload_id()
+    .ok_or_else(|| Error::Aws("S3 didn't return a multipart upload id".to_string()))?;
+
+  let completed = match upload_parts(client, bucket, artifact, upload_id, options).await {
+    Ok(parts) => options
+      .retry
+      .run(
+        || {
+          client
+            .complete_multipart_upload()
+            .bucket(bucket)
+            .key(&artifact.key)
+            .upload_id(upload_id)
+            .multipart_upload(
+              CompletedMultipartUpload::builder()
+                .set_parts(Some(parts.clone()))
+                .build(),
+            )
+            .send()
+        },
+        is_retryable,
+      )
+      .await
+      .map_err(map_sdk_error),
+    Err(e) => Err(e),
+  };
+  if let Err(e) = completed {
+    // the upload has already failed, so an abort failure has nothing to add
+    let _ = client
+      .abort_multipart_upload()
+      .bucket(bucket)
+      .key(&artifact.key)
+      .upload_id(upload_id)
+      .send()
+      .await;
+    return Err(e);
+  }
+  Ok(())
+}
 
-/// Uploads a buffer to AWS S3
+/// Uploads an artifact to AWS S3, skipping the upload if the object already has identical content
+/// unless `force` is set. Artifacts over the multipart threshold are sent in parts, everything else
+/// with a single PUT. Every request is retried on transient failures according to the retry
+/// policy.
 ///
 /// # Examples
 ///
 /// ```
-/// use push_fn_lib::upload::aws_s3::s3_upload;
+/// use push_fn_lib::{
+///   artifact::Artifact,
+///   upload::aws_s3::{s3_clients, s3_upload, S3UploadOptions},
+/// };
 /// use std::{fs::File, io::Read};
 ///
 /// async fn do_something() {
 ///     let file_name = "src/zip.rs";
 ///     let region = "eu-west-2".to_string();
 ///     let bucket = "fn-push-testing".to_string();
+///     let shared_config = aws_config::load_from_env().await;
+///     let clients = s3_clients(&shared_config, &[region.clone()]);
 ///     let mut file = File::open(file_name).unwrap();
 ///     let mut file_buf = Vec::new();
 ///     file.read_to_end(&mut file_buf).unwrap();
-///     s3_upload(&region, &bucket, &file_name.to_string(), file_buf.clone()).await;
+///     let artifact = Artifact::new(file_name.to_string(), file_buf);
+///     s3_upload(&clients[&region], &bucket, &artifact, &S3UploadOptions::default())
+///         .await
+///         .unwrap();
 /// }
 /// ```
-pub async fn s3_upload(region: &String, bucket: &String, key: &String, data: Vec<u8>) {
-  let region_provider = Region::new(region.to_owned());
-  let shared_config = aws_config::from_env().region(region_provider).load().await;
-  let client = Client::new(&shared_config);
-  let body = ByteStream::from(data);
-  client
-    .put_object()
-    .bucket(bucket)
-    .key(key)
-    .body(body)
-    .send()
+pub async fn s3_upload(
+  client: &Client,
+  bucket: &String,
+  artifact: &Artifact,
+  options: &S3UploadOptions,
+) -> Result<UploadStatus> {
+  if !options.force && is_unchanged(client, bucket, artifact).await {
+    return Ok(UploadStatus::Unchanged);
+  }
+  if artifact.data.size() > options.multipart_threshold {
+    multipart_upload(client, bucket, artifact, options).await?;
+    return Ok(UploadStatus::Uploaded);
+  }
+  let body = artifact.data.read_range(0..artifact.data.size())?;
+  options
+    .retry
+    .run(
+      || {
+        client
+          .put_object()
+          .bucket(bucket)
+          .key(&artifact.key)
+          .metadata(SHA256_METADATA_KEY, &artifact.sha256)
+          .body(ByteStream::from(body.clone()))
+          .send()
+      },
+      is_retryable,
+    )
     .await
-    .unwrap();
+    .map_err(map_sdk_error)?;
+  Ok(UploadStatus::Uploaded)
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use aws_sdk_s3::{
+    client::customize::Response, operation::put_object::PutObjectError, primitives::SdkBody,
+  };
+  use aws_smithy_types::error::ErrorMetadata;
   use std::{fs::File, io::Read};
 
   #[tokio::test]
@@ -55,7 +398,23 @@ mod tests {
     let mut file_buf = Vec::new();
     file.read_to_end(&mut file_buf).unwrap();
 
-    s3_upload(&region, &bucket, &file_name.to_string(), file_buf.clone()).await;
+    let artifact = Artifact::new(file_name.to_string(), file_buf.clone());
+    let force = S3UploadOptions {
+      force: true,
+      ..Default::default()
+    };
+    assert_eq!(
+      s3_upload(&client, &bucket, &artifact, &force)
+        .await
+        .unwrap(),
+      UploadStatus::Uploaded
+    );
+    assert_eq!(
+      s3_upload(&client, &bucket, &artifact, &S3UploadOptions::default())
+        .await
+        .unwrap(),
+      UploadStatus::Unchanged
+    );
 
     let object = client
       .get_object()
@@ -66,4 +425,69 @@ mod tests {
       .unwrap();
     assert_eq!(file_buf, object.body.collect().await.unwrap().into_bytes())
   }
+
+  fn service_error(status: u16, code: &str) -> SdkError<PutObjectError> {
+    let raw = http::Response::builder()
+      .status(status)
+      .body(SdkBody::empty())
+      .unwrap();
+    SdkError::service_error(
+      PutObjectError::generic(ErrorMetadata::builder().code(code).build()),
+      Response::new(raw),
+    )
+  }
+
+  #[test]
+  fn only_transient_errors_are_retried() {
+    assert!(is_retryable(&service_error(503, "SlowDown")));
+    assert!(is_retryable(&service_error(500, "InternalError")));
+    assert!(is_retryable(&service_error(400, "RequestTimeout")));
+    assert!(is_retryable(&SdkError::<PutObjectError>::timeout_error(
+      "timed out"
+    )));
+    assert!(!is_retryable(&service_error(403, "AccessDenied")));
+    assert!(!is_retryable(&service_error(404, "NoSuchBucket")));
+  }
+
+  #[test]
+  fn only_credential_failures_are_auth_errors() {
+    let credentials = SdkError::<PutObjectError>::construction_failure(
+      CredentialsError::not_loaded("no credentials in the environment"),
+    );
+    assert!(matches!(map_sdk_error(credentials), Error::Auth(_)));
+    let invalid = SdkError::<PutObjectError>::construction_failure("invalid bucket name");
+    assert!(matches!(map_sdk_error(invalid), Error::Aws(_)));
+    assert!(matches!(
+      map_sdk_error(service_error(403, "AccessDenied")),
+      Error::Auth(_)
+    ));
+  }
+
+  #[test]
+  fn legacy_location_constraints_map_to_regions() {
+    assert_eq!(constraint_region(None), "us-east-1");
+    assert_eq!(constraint_region(Some("")), "us-east-1");
+    assert_eq!(constraint_region(Some("EU")), "eu-west-1");
+    assert_eq!(constraint_region(Some("ap-south-1")), "ap-south-1");
+  }
+
+  #[test]
+  fn parts_cover_the_whole_archive() {
+    let mib = 1024 * 1024;
+    let ranges = part_ranges(12 * mib + 1, 5 * mib);
+    assert_eq!(
+      ranges,
+      vec![0..5 * mib, 5 * mib..10 * mib, 10 * mib..12 * mib + 1]
+    );
+    // parts smaller than S3 allows are bumped up to the minimum
+    assert_eq!(part_ranges(6 * mib, mib).len(), 2);
+  }
+
+  #[test]
+  fn parts_never_exceed_the_s3_limit() {
+    let len = MAX_PARTS * MIN_PART_SIZE * 3;
+    let ranges = part_ranges(len, MIN_PART_SIZE);
+    assert!(ranges.len() as u64 <= MAX_PARTS);
+    assert_eq!(ranges.last().unwrap().end, len);
+  }
 }
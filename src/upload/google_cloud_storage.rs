@@ -1,46 +1,333 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
 use google_cloud_default::WithAuthExt;
 use google_cloud_storage::client::{Client, ClientConfig};
-use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
+use google_cloud_storage::http::objects::{
+  get::GetObjectRequest,
+  upload::{Media, UploadObjectRequest, UploadType},
+};
+use reqwest::{
+  header::{CONTENT_LENGTH, CONTENT_RANGE, RANGE},
+  StatusCode,
+};
 
-/// Uploads a buffer to Google Cloud Storage.
+use crate::{
+  artifact::Artifact,
+  error::{Error, Result},
+  upload::{retry::RetryPolicy, UploadStatus},
+};
+
+/// Cloud Storage requires every chunk of a resumable upload except the last to be a multiple of
+/// this many bytes
+pub const CHUNK_ALIGNMENT: u64 = 256 * 1024;
+
+/// How an artifact is sent to Cloud Storage
+#[derive(Debug, Clone)]
+pub struct CsUploadOptions {
+  /// Upload even if the remote object already has identical content
+  pub force: bool,
+  /// Artifacts larger than this many bytes are sent in a resumable upload session
+  pub resumable_threshold: u64,
+  /// The size in bytes of each chunk of a resumable upload
+  pub chunk_size: u64,
+  /// How each request is retried after a transient failure
+  pub retry: RetryPolicy,
+}
+
+impl Default for CsUploadOptions {
+  fn default() -> Self {
+    CsUploadOptions {
+      force: false,
+      resumable_threshold: 8 * 1024 * 1024,
+      chunk_size: 8 * 1024 * 1024,
+      retry: RetryPolicy::default(),
+    }
+  }
+}
+
+/// Maps a Cloud Storage client error onto our error type, separating credential problems from
+/// other failures
+pub(crate) fn map_http_error(err: google_cloud_storage::http::Error) -> Error {
+  use google_cloud_storage::http::Error as HttpError;
+  let message = err.to_string();
+  let status = match &err {
+    HttpError::Response(response) => Some(response.code),
+    HttpError::HttpClient(e) => e.status().map(|s| s.as_u16()),
+    HttpError::TokenSource(_) => return Error::Auth(message),
+  };
+  match status {
+    Some(401) | Some(403) => Error::Auth(message),
+    _ => Error::Gcs(message),
+  }
+}
+
+/// Whether a failed Cloud Storage request is worth trying again: timeouts, dropped connections,
+/// throttling and server errors are, while credential problems and client errors are not
+pub(crate) fn is_retryable(err: &google_cloud_storage::http::Error) -> bool {
+  use google_cloud_storage::http::Error as HttpError;
+  match err {
+    HttpError::Response(response) => response.is_retriable(),
+    HttpError::HttpClient(e) => is_transient(e),
+    HttpError::TokenSource(_) => false,
+  }
+}
+
+/// Creates a Cloud Storage client using the default credentials chain, to be shared by every upload
 ///
 /// # Examples
 ///
 /// ```
-/// use push_fn_lib::upload::google_cloud_storage::cs_upload;
+/// use push_fn_lib::upload::google_cloud_storage::cs_client;
+///
+/// async fn do_something() {
+///     let client = cs_client().await.unwrap();
+/// }
+/// ```
+pub async fn cs_client() -> Result<Client> {
+  let config = ClientConfig::default()
+    .with_auth()
+    .await
+    .map_err(|e| Error::Auth(e.to_string()))?;
+  Ok(Client::new(config))
+}
+
+/// Checks whether the object at `key` already has the same MD5 as the artifact, which Cloud Storage
+/// computes for every object uploaded in one piece. Any failure to read the object's metadata just
+/// means we can't prove it is unchanged.
+async fn is_unchanged(client: &Client, bucket: &String, artifact: &Artifact) -> bool {
+  let object = client
+    .get_object(&GetObjectRequest {
+      bucket: bucket.to_string(),
+      object: artifact.key.to_string(),
+      ..Default::default()
+    })
+    .await;
+  object
+    .ok()
+    .and_then(|object| STANDARD.decode(object.md5_hash?).ok())
+    .is_some_and(|remote_md5| hex::encode(remote_md5) == artifact.md5)
+}
+
+/// Where a resumable upload session has got to
+#[derive(Debug, PartialEq, Eq)]
+enum SessionState {
+  /// The object has been created
+  Complete,
+  /// Cloud Storage has persisted everything before this offset
+  Incomplete(u64),
+}
+
+/// Whether a failed request is worth retrying, or a failed chunk worth resuming from the last
+/// persisted byte
+fn is_transient(err: &reqwest::Error) -> bool {
+  err.is_timeout()
+    || err.is_connect()
+    || err.is_request()
+    || err.status().is_some_and(|status| {
+      status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+    })
+}
+
+fn map_session_error(err: reqwest::Error) -> Error {
+  match err.status().map(|s| s.as_u16()) {
+    Some(401) | Some(403) => Error::Auth(err.to_string()),
+    _ => Error::Gcs(err.to_string()),
+  }
+}
+
+/// Reads the session state from a resumable upload response. An incomplete upload comes back as a
+/// 308 with a `Range: bytes=0-N` header naming the last persisted byte, or no header if nothing has
+/// been persisted yet.
+fn session_state(response: reqwest::Response) -> std::result::Result<SessionState, reqwest::Error> {
+  if response.status() != StatusCode::PERMANENT_REDIRECT {
+    response.error_for_status()?;
+    return Ok(SessionState::Complete);
+  }
+  let persisted = response
+    .headers()
+    .get(RANGE)
+    .and_then(|range| range.to_str().ok())
+    .and_then(|range| range.rsplit('-').next())
+    .and_then(|last_byte| last_byte.parse::<u64>().ok())
+    .map_or(0, |last_byte| last_byte + 1);
+  Ok(SessionState::Incomplete(persisted))
+}
+
+/// Sends one chunk of a resumable upload starting at `offset`
+async fn upload_chunk(
+  http: &reqwest::Client,
+  session_url: &str,
+  chunk: Bytes,
+  offset: u64,
+  total: u64,
+) -> std::result::Result<SessionState, reqwest::Error> {
+  let end = offset + chunk.len() as u64;
+  let response = http
+    .put(session_url)
+    .header(
+      CONTENT_RANGE,
+      format!("bytes {}-{}/{}", offset, end - 1, total),
+    )
+    .header(CONTENT_LENGTH, end - offset)
+    .body(chunk)
+    .send()
+    .await?;
+  session_state(response)
+}
+
+/// Asks Cloud Storage how much of a resumable upload it has persisted
+async fn query_session(
+  http: &reqwest::Client,
+  session_url: &str,
+  total: u64,
+) -> std::result::Result<SessionState, reqwest::Error> {
+  let response = http
+    .put(session_url)
+    .header(CONTENT_RANGE, format!("bytes */{}", total))
+    .header(CONTENT_LENGTH, 0)
+    .send()
+    .await?;
+  session_state(response)
+}
+
+/// Uploads an artifact in chunks through a resumable upload session. When a chunk fails with a
+/// transient error the session is queried after backing off and the upload picks up from the last
+/// persisted byte rather than starting over. The retry policy's attempts limit how many times in a
+/// row a chunk can fail or be sent without any more being persisted, after which the session is
+/// cancelled.
+async fn resumable_upload(
+  client: &Client,
+  bucket: &String,
+  artifact: &Artifact,
+  options: &CsUploadOptions,
+) -> Result<()> {
+  let request = UploadObjectRequest {
+    bucket: bucket.to_string(),
+    ..Default::default()
+  };
+  let upload_type = UploadType::Simple(Media::new(artifact.key.to_string()));
+  let session = options
+    .retry
+    .run(
+      || client.prepare_resumable_upload(&request, &upload_type),
+      is_retryable,
+    )
+    .await
+    .map_err(map_http_error)?;
+  // the session url authorises the upload by itself, so plain requests are enough from here on
+  let http = reqwest::Client::new();
+  let total = artifact.data.size();
+  let chunk_size = (options.chunk_size / CHUNK_ALIGNMENT).max(1) * CHUNK_ALIGNMENT;
+  let mut offset = 0;
+  // how many times in a row a chunk has failed or come back without anything more persisted
+  let mut stalls = 0;
+  loop {
+    let chunk = artifact
+      .data
+      .read_range(offset..(offset + chunk_size).min(total))?;
+    let result = match upload_chunk(&http, session.url(), chunk, offset, total).await {
+      Err(e) if is_transient(&e) && stalls + 1 < options.retry.max_attempts => {
+        tokio::time::sleep(options.retry.delay(stalls)).await;
+        match query_session(&http, session.url(), total).await {
+          Err(e) if is_transient(&e) => Ok(SessionState::Incomplete(offset)),
+          result => result,
+        }
+      }
+      result => result,
+    };
+    let state = match result {
+      Ok(state) => state,
+      Err(e) => {
+        // the upload has already failed, so a cancel failure has nothing to add
+        let _ = session.cancel().await;
+        return Err(map_session_error(e));
+      }
+    };
+    match state {
+      SessionState::Complete => return Ok(()),
+      SessionState::Incomplete(persisted) if persisted > offset => {
+        stalls = 0;
+        offset = persisted;
+      }
+      SessionState::Incomplete(persisted) => {
+        stalls += 1;
+        if stalls >= options.retry.max_attempts {
+          let _ = session.cancel().await;
+          return Err(Error::Gcs(format!(
+            "the resumable upload of {} made no progress past byte {} in {} attempts",
+            artifact.key, persisted, stalls
+          )));
+        }
+        offset = persisted;
+      }
+    }
+  }
+}
+
+/// Uploads an artifact to Google Cloud Storage, skipping the upload if the object already has
+/// identical content unless `force` is set. Artifacts over the resumable threshold are sent in
+/// chunks through a resumable upload session, everything else in a single request. Every request
+/// is retried on transient failures according to the retry policy.
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::{
+///   artifact::Artifact,
+///   upload::google_cloud_storage::{cs_client, cs_upload, CsUploadOptions},
+/// };
 /// use std::{fs::File, io::Read};
 ///
 /// async fn do_something() {
 ///     let file_name = "src/upload/google_cloud_storage.rs";
 ///     let bucket = "fn-push-testing".to_string();
+///     let client = cs_client().await.unwrap();
 ///     let mut file = File::open(file_name).unwrap();
 ///     let mut file_buf = Vec::new();
 ///     file.read_to_end(&mut file_buf).unwrap();
-///     cs_upload(&bucket, &file_name.to_string(), file_buf.clone()).await;
+///     let artifact = Artifact::new(file_name.to_string(), file_buf);
+///     cs_upload(&client, &bucket, &artifact, &CsUploadOptions::default())
+///         .await
+///         .unwrap();
 /// }
 /// ```
-pub async fn cs_upload(bucket: &String, key: &String, data: Vec<u8>) {
-  let config = ClientConfig::default().with_auth().await.unwrap();
-  let client = Client::new(config);
+pub async fn cs_upload(
+  client: &Client,
+  bucket: &String,
+  artifact: &Artifact,
+  options: &CsUploadOptions,
+) -> Result<UploadStatus> {
+  if !options.force && is_unchanged(client, bucket, artifact).await {
+    return Ok(UploadStatus::Unchanged);
+  }
+  if artifact.data.size() > options.resumable_threshold {
+    resumable_upload(client, bucket, artifact, options).await?;
+    return Ok(UploadStatus::Uploaded);
+  }
 
   // Upload the file
-  let upload_type = UploadType::Simple(Media::new(key.to_string()));
-  client
-    .upload_object(
-      &UploadObjectRequest {
-        bucket: bucket.to_string(),
-        ..Default::default()
-      },
-      data,
-      &upload_type,
+  let request = UploadObjectRequest {
+    bucket: bucket.to_string(),
+    ..Default::default()
+  };
+  let upload_type = UploadType::Simple(Media::new(artifact.key.to_string()));
+  let body = artifact.data.read_range(0..artifact.data.size())?;
+  options
+    .retry
+    .run(
+      || client.upload_object(&request, body.clone(), &upload_type),
+      is_retryable,
     )
     .await
-    .unwrap();
+    .map_err(map_http_error)?;
+  Ok(UploadStatus::Uploaded)
 }
 
 #[cfg(test)]
 mod tests {
-  use google_cloud_storage::http::objects::{download::Range, get::GetObjectRequest};
+  use google_cloud_storage::http::objects::download::Range;
 
   use super::*;
   use std::{fs::File, io::Read};
@@ -65,7 +352,23 @@ mod tests {
     let mut file_buf = Vec::new();
     file.read_to_end(&mut file_buf).unwrap();
 
-    cs_upload(&bucket, &file_name.to_string(), file_buf.clone()).await;
+    let artifact = Artifact::new(file_name.to_string(), file_buf.clone());
+    let force = CsUploadOptions {
+      force: true,
+      ..Default::default()
+    };
+    assert_eq!(
+      cs_upload(&client, &bucket, &artifact, &force)
+        .await
+        .unwrap(),
+      UploadStatus::Uploaded
+    );
+    assert_eq!(
+      cs_upload(&client, &bucket, &artifact, &CsUploadOptions::default())
+        .await
+        .unwrap(),
+      UploadStatus::Unchanged
+    );
 
     let data = client
       .download_object(
@@ -79,4 +382,50 @@ mod tests {
       .await;
     assert_eq!(file_buf, data.unwrap())
   }
+
+  fn response(status: u16, range: Option<&str>) -> reqwest::Response {
+    let mut builder = http::Response::builder().status(status);
+    if let Some(range) = range {
+      builder = builder.header(RANGE, range);
+    }
+    reqwest::Response::from(builder.body(Vec::new()).unwrap())
+  }
+
+  #[test]
+  fn only_transient_errors_are_retried() {
+    use google_cloud_storage::http::{error::ErrorResponse, Error as HttpError};
+    let error = |code| {
+      HttpError::Response(ErrorResponse {
+        code,
+        errors: Vec::new(),
+        message: String::new(),
+      })
+    };
+    assert!(is_retryable(&error(503)));
+    assert!(is_retryable(&error(429)));
+    assert!(!is_retryable(&error(403)));
+    assert!(!is_retryable(&error(404)));
+    assert!(!is_retryable(&HttpError::TokenSource("expired".into())));
+  }
+
+  #[test]
+  fn session_state_reads_the_persisted_range() {
+    assert_eq!(
+      session_state(response(308, Some("bytes=0-262143"))).unwrap(),
+      SessionState::Incomplete(262144)
+    );
+    assert_eq!(
+      session_state(response(308, None)).unwrap(),
+      SessionState::Incomplete(0)
+    );
+    assert_eq!(
+      session_state(response(200, None)).unwrap(),
+      SessionState::Complete
+    );
+    let err = session_state(response(503, None)).unwrap_err();
+    assert!(is_transient(&err));
+    let err = session_state(response(403, None)).unwrap_err();
+    assert!(!is_transient(&err));
+    assert!(matches!(map_session_error(err), Error::Auth(_)));
+  }
 }
@@ -0,0 +1,157 @@
+use std::{future::Future, time::Duration};
+
+use rand::Rng;
+use tokio::time::sleep;
+
+/// How often and how patiently a request is retried after a transient failure
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::upload::retry::RetryPolicy;
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy {
+///   max_attempts: 5,
+///   base_delay: Duration::from_millis(100),
+///   max_delay: Duration::from_secs(1),
+///   jitter: false,
+/// };
+/// assert_eq!(policy.delay(0), Duration::from_millis(100));
+/// assert_eq!(policy.delay(2), Duration::from_millis(400));
+/// assert_eq!(policy.delay(8), Duration::from_secs(1));
+/// ```
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+  /// The total number of attempts, including the first
+  pub max_attempts: u32,
+  /// The delay before the first retry, doubled for each one after
+  pub base_delay: Duration,
+  /// The longest we'll ever wait between attempts
+  pub max_delay: Duration,
+  /// Pick a random delay up to the backoff rather than the backoff itself, so that concurrent
+  /// uploads hitting the same throttling don't all retry in lockstep
+  pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      max_attempts: 4,
+      base_delay: Duration::from_millis(250),
+      max_delay: Duration::from_secs(20),
+      jitter: true,
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// How long to wait before retrying after the given (zero based) failed attempt
+  pub fn delay(&self, attempt: u32) -> Duration {
+    let backoff = self
+      .base_delay
+      .saturating_mul(2u32.saturating_pow(attempt))
+      .min(self.max_delay);
+    match self.jitter {
+      true => backoff.mul_f64(rand::thread_rng().gen_range(0.0..=1.0)),
+      false => backoff,
+    }
+  }
+
+  /// Runs `operation` until it succeeds, fails with an error `is_retryable` says is fatal, or runs
+  /// out of attempts, backing off between attempts
+  pub async fn run<T, E, F, Fut>(
+    &self,
+    mut operation: F,
+    is_retryable: impl Fn(&E) -> bool,
+  ) -> Result<T, E>
+  where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+  {
+    let mut attempt = 0;
+    loop {
+      match operation().await {
+        Err(e) if attempt + 1 < self.max_attempts && is_retryable(&e) => {
+          sleep(self.delay(attempt)).await;
+          attempt += 1;
+        }
+        result => return result,
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::cell::Cell;
+
+  use super::*;
+
+  fn quick_policy(max_attempts: u32) -> RetryPolicy {
+    RetryPolicy {
+      max_attempts,
+      base_delay: Duration::from_millis(1),
+      max_delay: Duration::from_millis(2),
+      jitter: true,
+    }
+  }
+
+  #[test]
+  fn jitter_stays_within_the_backoff() {
+    let policy = RetryPolicy::default();
+    for attempt in 0..10 {
+      let delay = policy.delay(attempt);
+      assert!(delay <= policy.max_delay);
+      assert!(delay <= policy.base_delay * 2u32.pow(attempt));
+    }
+  }
+
+  #[tokio::test]
+  async fn transient_failures_are_retried() {
+    let calls = Cell::new(0);
+    let result: Result<u32, &str> = quick_policy(3)
+      .run(
+        || {
+          calls.set(calls.get() + 1);
+          let call = calls.get();
+          async move {
+            match call {
+              3 => Ok(call),
+              _ => Err("503"),
+            }
+          }
+        },
+        |e| *e == "503",
+      )
+      .await;
+    assert_eq!(result, Ok(3));
+  }
+
+  #[tokio::test]
+  async fn fatal_failures_and_exhausted_attempts_give_up() {
+    let calls = Cell::new(0);
+    let result: Result<(), &str> = quick_policy(5)
+      .run(
+        || {
+          calls.set(calls.get() + 1);
+          async { Err("403") }
+        },
+        |e| *e == "503",
+      )
+      .await;
+    assert_eq!((result, calls.get()), (Err("403"), 1));
+
+    calls.set(0);
+    let result: Result<(), &str> = quick_policy(2)
+      .run(
+        || {
+          calls.set(calls.get() + 1);
+          async { Err("503") }
+        },
+        |e| *e == "503",
+      )
+      .await;
+    assert_eq!((result, calls.get()), (Err("503"), 2));
+  }
+}
@@ -1,13 +1,31 @@
 use std::{
   collections::HashSet,
-  fs::{self},
-  io::{Cursor, Write},
-  path::{Path, PathBuf},
+  env,
+  fs::{self, File},
+  io::{self, Cursor, Seek, Write},
+  path::{Component, Path, PathBuf},
 };
 
-use zip::{write::FileOptions, ZipWriter};
+use rayon::prelude::*;
+use tempfile::SpooledTempFile;
+use time::OffsetDateTime;
+use zip::{write::SimpleFileOptions, CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+pub use crate::args::{ArchiveArgs, Compression, SymlinkMode};
+use crate::{
+  error::{Error, Result},
+  search::{canonical_base, PatternList},
+};
+
+/// How many files each worker thread compresses per batch
+const ENTRIES_PER_THREAD: usize = 16;
+
+/// How large a compressed entry can get in memory before it's spooled to a temporary file, when
+/// spooling is asked for
+const ENTRY_SPOOL_LIMIT: usize = 1024 * 1024;
 
 /// Metadata for the symlink to direct resolvers to resources in a layer
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SymLink {
   /// The path at which the symlink should be written
   pub path: String,
@@ -15,31 +33,374 @@ pub struct SymLink {
   pub target: String,
 }
 
-fn fill_zip(
-  files: &HashSet<String>,
-  archive: &mut Cursor<Vec<u8>>,
-  base: PathBuf,
+/// A file to add to an archive, read from anywhere on disk and placed wherever it's named
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipSource {
+  /// Where the file is read from
+  pub path: PathBuf,
+  /// The file's path within the archive, under the root dir if there is one
+  pub name: PathBuf,
+}
+
+/// Options controlling where and how files are written into the archive
+#[derive(Debug, Clone)]
+pub struct ZipOptions {
+  /// An optional path within the zip to save the files (and symlink) to
+  pub root_dir: Option<PathBuf>,
+  /// Sort entries and normalise their timestamps, permissions and path separators so that the
+  /// same files always produce a byte-identical archive
+  pub reproducible: bool,
+  /// How symlinks found in the source tree are treated
+  pub symlinks: SymlinkMode,
+  /// Skip files matched by ignore files under the input path, see [`crate::search::search`]
+  pub ignore_files: bool,
+  /// Match the include and exclude globs regardless of case
+  pub ignore_case: bool,
+  /// Files matching any of these (relative to the input path) are made executable, whatever their
+  /// permissions on disk
+  pub executables: PatternList,
+  /// How the entries are compressed
+  pub compression: Compression,
+  /// The compression level, or the method's own default if `None`
+  pub compression_level: Option<i32>,
+  /// Files matching any of these (relative to the input path) are already compressed, so they're
+  /// stored as they are
+  pub stored: PatternList,
+  /// Spool the archive, and any large compressed entries, to temporary files instead of building
+  /// them in memory
+  pub spool: bool,
+}
+
+impl Default for ZipOptions {
+  fn default() -> Self {
+    ZipOptions {
+      root_dir: None,
+      reproducible: true,
+      symlinks: SymlinkMode::Follow,
+      ignore_files: false,
+      ignore_case: false,
+      executables: PatternList::default(),
+      compression: Compression::Deflate,
+      compression_level: None,
+      stored: PatternList::default(),
+      spool: false,
+    }
+  }
+}
+
+impl ZipOptions {
+  /// The options given on the command line, placing the files under `root_dir` if there is one
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use std::path::Path;
+  /// use push_fn_lib::zip::{ArchiveArgs, ZipOptions};
+  ///
+  /// let options = ZipOptions::new(&ArchiveArgs::default(), None).unwrap();
+  /// assert!(options.stored.is_match(Path::new("public/logo.png")));
+  /// assert!(!options.stored.is_match(Path::new("index.js")));
+  /// ```
+  pub fn new(args: &ArchiveArgs, root_dir: Option<PathBuf>) -> Result<Self> {
+    validate_compression_level(args.compression, args.compression_level)?;
+    Ok(ZipOptions {
+      root_dir,
+      reproducible: args.reproducible,
+      symlinks: args.symlinks,
+      ignore_files: args.ignore_files,
+      ignore_case: args.ignore_case,
+      executables: parse_patterns(&args.exec, args.ignore_case)?,
+      compression: args.compression,
+      compression_level: args.compression_level,
+      stored: parse_patterns(&args.store, args.ignore_case)?,
+      spool: args.spool,
+    })
+  }
+}
+
+impl From<Compression> for CompressionMethod {
+  fn from(compression: Compression) -> Self {
+    match compression {
+      Compression::Stored => CompressionMethod::Stored,
+      Compression::Deflate => CompressionMethod::Deflated,
+      Compression::Zstd => CompressionMethod::Zstd,
+      Compression::Bzip2 => CompressionMethod::Bzip2,
+    }
+  }
+}
+
+/// Checks that a compression level is in range for the compression method
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::zip::{validate_compression_level, Compression};
+///
+/// assert!(validate_compression_level(Compression::Deflate, Some(9)).is_ok());
+/// assert!(validate_compression_level(Compression::Zstd, Some(19)).is_ok());
+/// assert!(validate_compression_level(Compression::Deflate, Some(19)).is_err());
+/// // the zip crate has no deflate level 0; stored compression leaves files uncompressed
+/// assert!(validate_compression_level(Compression::Deflate, Some(0)).is_err());
+/// assert!(validate_compression_level(Compression::Stored, Some(1)).is_err());
+/// ```
+pub fn validate_compression_level(compression: Compression, level: Option<i32>) -> Result<()> {
+  let range = match compression {
+    Compression::Stored if level.is_some() => {
+      return Err(Error::InvalidArgs(
+        "--compression-level can't be used with stored compression".to_string(),
+      ))
+    }
+    Compression::Stored => return Ok(()),
+    Compression::Deflate => 1..=9,
+    Compression::Zstd => 1..=22,
+    Compression::Bzip2 => 1..=9,
+  };
+  match level {
+    Some(level) if !range.contains(&level) => Err(Error::InvalidArgs(format!(
+      "--compression-level {} is out of range for {:?} compression ({} to {})",
+      level,
+      compression,
+      range.start(),
+      range.end()
+    ))),
+    _ => Ok(()),
+  }
+}
+
+/// Parses the globs for `--exec` and `--store`, where an empty glob (as in `--store ''`) stands for
+/// none at all
+fn parse_patterns(globs: &[String], ignore_case: bool) -> Result<PatternList> {
+  let globs: Vec<String> = globs
+    .iter()
+    .filter(|glob| !glob.is_empty())
+    .cloned()
+    .collect();
+  PatternList::new(&globs, ignore_case)
+}
+
+/// The modification time stamped on every entry of a reproducible archive. This is taken from
+/// `SOURCE_DATE_EPOCH` when it is set, and is otherwise the earliest time a zip can represent.
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::zip::reproducible_mtime;
+///
+/// let mtime = reproducible_mtime(Some("1700000000")).unwrap();
+/// assert_eq!((mtime.year(), mtime.month(), mtime.day()), (2023, 11, 14));
+/// assert_eq!(reproducible_mtime(None).unwrap().year(), 1980);
+/// ```
+pub fn reproducible_mtime(source_date_epoch: Option<&str>) -> Result<DateTime> {
+  let Some(epoch) = source_date_epoch else {
+    return Ok(DateTime::default());
+  };
+  let invalid = || {
+    Error::InvalidArgs(format!(
+      "SOURCE_DATE_EPOCH {:?} is not a valid timestamp",
+      epoch
+    ))
+  };
+  let seconds: i64 = epoch.trim().parse().map_err(|_| invalid())?;
+  let time = OffsetDateTime::from_unix_timestamp(seconds).map_err(|_| invalid())?;
+  // zip timestamps can't go back further than 1980, so clamp anything earlier
+  Ok(DateTime::try_from(time).unwrap_or_default())
+}
+
+/// Checks that a `--root-dir` value is a relative path that stays inside the archive, returning it
+/// with any `.` components removed. An empty or `.` root dir yields `None`.
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::zip::validate_root_dir;
+/// use std::path::PathBuf;
+///
+/// assert_eq!(validate_root_dir("./app").unwrap(), Some(PathBuf::from("app")));
+/// assert!(validate_root_dir("../app").is_err());
+/// ```
+pub fn validate_root_dir(root_dir: &str) -> Result<Option<PathBuf>> {
+  let mut prefix = PathBuf::new();
+  for component in Path::new(root_dir).components() {
+    match component {
+      Component::Normal(part) => prefix.push(part),
+      Component::CurDir => {}
+      _ => {
+        return Err(Error::InvalidArgs(format!(
+          "root dir {:?} must be a relative path without `..` components",
+          root_dir
+        )))
+      }
+    }
+  }
+  match prefix.as_os_str().is_empty() {
+    true => Ok(None),
+    false => Ok(Some(prefix)),
+  }
+}
+
+fn entry_name(options: &ZipOptions, file_path: &Path) -> Option<String> {
+  let path = match &options.root_dir {
+    Some(root) => root.join(file_path),
+    None => file_path.to_path_buf(),
+  };
+  match options.reproducible {
+    // zip entries should always use forward slashes, whichever platform built them
+    true => path
+      .components()
+      .filter_map(|c| match c {
+        Component::Normal(part) => Some(part.to_str()),
+        _ => None,
+      })
+      .collect::<Option<Vec<&str>>>()
+      .map(|parts| parts.join("/")),
+    false => path.to_str().map(String::from),
+  }
+}
+
+/// The permission bits of a file on disk
+#[cfg(unix)]
+fn source_mode(path: &Path) -> Result<u32> {
+  use std::os::unix::fs::PermissionsExt;
+  let metadata = fs::metadata(path).map_err(|e| Error::io(path, e))?;
+  Ok(metadata.permissions().mode() & 0o777)
+}
+
+/// Other platforms don't track the permission bits we need, so files are left as plain files
+/// unless they're matched by an `--exec` glob
+#[cfg(not(unix))]
+fn source_mode(_path: &Path) -> Result<u32> {
+  Ok(0o644)
+}
+
+/// The permissions to store for a file. Reproducible archives only keep whether the file is
+/// executable, so that they don't depend on the umask of whoever checked the files out.
+fn file_mode(options: &ZipOptions, file_path: &Path, full_path: &Path) -> Result<u32> {
+  if options.executables.is_match(file_path) {
+    return Ok(0o755);
+  }
+  let mode = source_mode(full_path)?;
+  match options.reproducible {
+    true if mode & 0o111 != 0 => Ok(0o755),
+    true => Ok(0o644),
+    false => Ok(mode),
+  }
+}
+
+/// Sets how a file is compressed, storing it as it is if it's already compressed
+fn compression_options(
+  options: &ZipOptions,
+  file_path: &Path,
+  file_options: SimpleFileOptions,
+) -> SimpleFileOptions {
+  match options.stored.is_match(file_path) {
+    true => file_options.compression_method(CompressionMethod::Stored),
+    false => file_options
+      .compression_method(options.compression.into())
+      .compression_level(options.compression_level.map(i64::from)),
+  }
+}
+
+/// Streams and compresses one file into an archive of its own, so that files can be compressed in
+/// parallel and then copied into the real archive in order without being compressed again
+fn compress_entry(
+  name: &str,
+  file_path: &Path,
+  full_path: &Path,
+  file_options: SimpleFileOptions,
+  options: &ZipOptions,
+) -> Result<SpooledTempFile> {
+  let mode = file_mode(options, file_path, full_path)?;
+  let mut source = File::open(full_path).map_err(|e| Error::io(full_path, e))?;
+  let spool_limit = match options.spool {
+    true => ENTRY_SPOOL_LIMIT,
+    false => usize::MAX,
+  };
+  let mut single = ZipWriter::new(tempfile::spooled_tempfile(spool_limit));
+  single.start_file(
+    name,
+    compression_options(options, file_path, file_options.unix_permissions(mode)),
+  )?;
+  io::copy(&mut source, &mut single).map_err(|e| Error::io(full_path, e))?;
+  Ok(single.finish()?)
+}
+
+/// Writes a zip of `sources` into `archive`, returning it once the archive is finished. Unlike
+/// [`write_zip`], the files can come from anywhere and be placed anywhere in the archive.
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::zip::{write_sources, ZipOptions, ZipSource};
+/// use std::{io::Cursor, path::PathBuf};
+///
+/// let sources = [ZipSource {
+///   path: PathBuf::from("src/zip.rs"),
+///   name: PathBuf::from("lib/zip.rs"),
+/// }];
+/// let archive = write_sources(&sources, None, &ZipOptions::default(), Cursor::new(Vec::new()));
+/// assert!(!archive.unwrap().into_inner().is_empty());
+/// ```
+pub fn write_sources<W: Write + Seek>(
+  sources: &[ZipSource],
   symlink: Option<SymLink>,
-) {
+  options: &ZipOptions,
+  archive: W,
+) -> Result<W> {
   let mut zip = ZipWriter::new(archive);
-  files.iter().for_each(|f| {
-    let full_path = Path::new(f);
-    let mut file_path = Path::new(f);
-    if full_path.is_absolute() {
-      file_path = full_path.strip_prefix(&base).unwrap();
+  let mut entries = Vec::with_capacity(sources.len());
+  for source in sources {
+    let name = entry_name(options, &source.name).ok_or_else(|| {
+      Error::io(
+        &source.path,
+        io::Error::new(io::ErrorKind::InvalidData, "path is not valid UTF-8"),
+      )
+    })?;
+    entries.push((name, source.name.as_path(), source.path.as_path()));
+  }
+  let (file_options, link_options) = match options.reproducible {
+    true => {
+      entries.sort();
+      let mtime = reproducible_mtime(env::var("SOURCE_DATE_EPOCH").ok().as_deref())?;
+      (
+        SimpleFileOptions::default().last_modified_time(mtime),
+        SimpleFileOptions::default()
+          .last_modified_time(mtime)
+          .unix_permissions(0o777),
+      )
     }
-    let contents = fs::read(full_path).unwrap();
-    let options = FileOptions::default();
-    zip
-      .start_file(file_path.to_str().unwrap(), options)
-      .unwrap();
-    zip.write_all(&contents).unwrap();
-  });
+    false => (SimpleFileOptions::default(), SimpleFileOptions::default()),
+  };
+  // files are read and compressed in parallel a batch at a time, which keeps the order of the
+  // entries (and so the archive) deterministic without holding every compressed file at once
+  let batch_size = rayon::current_num_threads() * ENTRIES_PER_THREAD;
+  for batch in entries.chunks(batch_size) {
+    let compressed = batch
+      .par_iter()
+      .map(|(name, file_path, full_path)| {
+        match options.symlinks == SymlinkMode::Preserve && full_path.is_symlink() {
+          true => Ok(None),
+          false => compress_entry(name, file_path, full_path, file_options, options).map(Some),
+        }
+      })
+      .collect::<Result<Vec<Option<SpooledTempFile>>>>()?;
+    for ((name, _, full_path), compressed) in batch.iter().zip(compressed) {
+      match compressed {
+        Some(single) => {
+          let mut single = ZipArchive::new(single)?;
+          zip.raw_copy_file(single.by_index_raw(0)?)?;
+        }
+        None => {
+          let target = fs::read_link(full_path).map_err(|e| Error::io(full_path, e))?;
+          zip.add_symlink(name, target.to_string_lossy(), link_options)?;
+        }
+      }
+    }
+  }
   if let Some(link) = symlink {
-    let options = FileOptions::default();
-    zip.add_symlink(link.path, link.target, options).unwrap();
+    let path = entry_name(options, Path::new(&link.path)).unwrap_or(link.path);
+    zip.add_symlink(path, link.target, link_options)?;
   }
-  zip.finish().unwrap();
+  Ok(zip.finish()?)
 }
 
 /// Creates a zip file from a set of files.
@@ -47,21 +408,90 @@ fn fill_zip(
 /// # Examples
 ///
 /// ```
-/// use push_fn_lib::zip::create_zip;
+/// use push_fn_lib::zip::{create_zip, ZipOptions};
 /// use std::collections::HashSet;
 ///
 /// let file_name: &str = "src/zip.rs";
 /// let mut files: HashSet<String> = HashSet::new();
 /// files.insert(file_name.to_string());
-/// let result: Vec<u8> = create_zip(&".".to_string(), files, None);
+/// let result: Vec<u8> = create_zip(".", files, None, &ZipOptions::default()).unwrap();
 /// ```
-pub fn create_zip(path: &String, files: HashSet<String>, symlink: Option<SymLink>) -> Vec<u8> {
-  let p = Path::new(path);
-  let base = fs::canonicalize(p).unwrap();
-  let buffer: Vec<u8> = Vec::new();
-  let mut archive: Cursor<Vec<u8>> = Cursor::new(buffer);
-  fill_zip(&files, &mut archive, base, symlink);
-  archive.into_inner()
+pub fn create_zip(
+  path: &str,
+  files: HashSet<String>,
+  symlink: Option<SymLink>,
+  options: &ZipOptions,
+) -> Result<Vec<u8>> {
+  let archive = write_zip(path, files, symlink, options, Cursor::new(Vec::new()))?;
+  Ok(archive.into_inner())
+}
+
+/// Writes a zip of a set of files into `archive`, returning it once the archive is finished. This
+/// lets an archive be streamed to a file rather than built up in memory.
+///
+/// # Examples
+///
+/// ```
+/// use push_fn_lib::zip::{write_zip, ZipOptions};
+/// use std::collections::HashSet;
+///
+/// let files = HashSet::from(["src/zip.rs".to_string()]);
+/// let file = tempfile::tempfile().unwrap();
+/// let file = write_zip(".", files, None, &ZipOptions::default(), file).unwrap();
+/// assert!(file.metadata().unwrap().len() > 0);
+/// ```
+pub fn write_zip<W: Write + Seek>(
+  path: &str,
+  files: HashSet<String>,
+  symlink: Option<SymLink>,
+  options: &ZipOptions,
+  archive: W,
+) -> Result<W> {
+  write_sources(&sources(path, files)?, symlink, options, archive)
+}
+
+/// Names each of `files` by its path relative to `path`, the directory they were found in. Absolute
+/// paths must be inside `path`, once any symlinks in the directories above them are resolved.
+pub fn sources(path: &str, files: HashSet<String>) -> Result<Vec<ZipSource>> {
+  let base = canonical_base(path)?;
+  files
+    .into_iter()
+    .map(|file| {
+      let path = PathBuf::from(file);
+      let name = match path.is_absolute() {
+        true => relative_to_base(&path, &base)?,
+        false => path.clone(),
+      };
+      Ok(ZipSource { path, name })
+    })
+    .collect()
+}
+
+/// The absolute `path` relative to the canonical `base`. The directory it's in is resolved the same
+/// way as the base if need be, but not the path itself, so that a symlink stays a symlink.
+fn relative_to_base(path: &Path, base: &Path) -> Result<PathBuf> {
+  if let Ok(relative) = path.strip_prefix(base) {
+    return Ok(relative.to_path_buf());
+  }
+  let resolved = match (path.parent(), path.file_name()) {
+    (Some(parent), Some(name)) => fs::canonicalize(parent)
+      .map(|parent| parent.join(name))
+      .ok(),
+    _ => None,
+  };
+  match resolved
+    .as_deref()
+    .map(|resolved| resolved.strip_prefix(base))
+  {
+    Some(Ok(relative)) => Ok(relative.to_path_buf()),
+    _ => Err(Error::io(
+      path,
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("path is outside the input path {:?}", base),
+      ),
+    )),
+  }
 }
 
 #[cfg(test)]
@@ -74,13 +504,14 @@ mod tests {
   use zip::ZipArchive;
 
   use super::*;
+  use crate::testing::temp_dir;
 
   #[test]
   fn zip_contains_expected_files() {
     let file_name = "src/zip.rs";
     let mut files = HashSet::new();
     files.insert(file_name.to_string());
-    let result = create_zip(&".".to_string(), files, None);
+    let result = create_zip(".", files, None, &ZipOptions::default()).unwrap();
 
     // Create a buffer to hold the file from the zip
     let mut content_buf = Cursor::new(Vec::new());
@@ -104,4 +535,229 @@ mod tests {
     // Compare the content we got from the archive to the content we got by reading from disk
     assert_eq!(file_buf, content_buf.into_inner());
   }
+
+  #[test]
+  fn zip_reports_missing_files() {
+    let mut files = HashSet::new();
+    files.insert("src/does-not-exist.rs".to_string());
+    let result = create_zip(".", files, None, &ZipOptions::default());
+    assert!(matches!(result, Err(Error::Io { .. })));
+  }
+
+  #[test]
+  fn files_are_named_relative_to_an_input_path_with_parents() {
+    let crate_dir = fs::canonicalize(env::current_dir().unwrap()).unwrap();
+    let input_path = format!(
+      "../{}/src",
+      crate_dir.file_name().unwrap().to_string_lossy()
+    );
+    let files = crate::search::search(
+      &input_path,
+      &["zip.rs".to_string()],
+      &[],
+      SymlinkMode::Follow,
+      false,
+      false,
+    )
+    .unwrap();
+    assert_eq!(
+      sources(&input_path, files).unwrap(),
+      [ZipSource {
+        path: crate_dir.join("src/zip.rs"),
+        name: PathBuf::from("zip.rs"),
+      }]
+    );
+
+    let outside = HashSet::from([crate_dir.join("Cargo.toml").display().to_string()]);
+    assert!(matches!(
+      sources(&input_path, outside),
+      Err(Error::Io { .. })
+    ));
+  }
+
+  #[test]
+  fn zip_writes_files_under_root_dir() {
+    let file_name = "src/zip.rs";
+    let mut files = HashSet::new();
+    files.insert(file_name.to_string());
+    let options = ZipOptions {
+      root_dir: validate_root_dir("./app").unwrap(),
+      ..Default::default()
+    };
+    let symlink = SymLink {
+      path: "node_modules".to_string(),
+      target: "/opt/nodejs/node_modules".to_string(),
+    };
+    let result = create_zip(".", files, Some(symlink), &options).unwrap();
+
+    let mut zip = ZipArchive::new(Cursor::new(result)).unwrap();
+    let names: HashSet<&str> = zip.file_names().collect();
+    assert!(names.contains("app/src/zip.rs"));
+    assert!(names.contains("app/node_modules"));
+    assert!(zip.by_name(file_name).is_err());
+  }
+
+  #[test]
+  fn root_dir_must_stay_inside_the_archive() {
+    assert_eq!(validate_root_dir(".").unwrap(), None);
+    assert_eq!(
+      validate_root_dir("app/lib").unwrap(),
+      Some(PathBuf::from("app").join("lib"))
+    );
+    assert!(matches!(
+      validate_root_dir("app/../../etc"),
+      Err(Error::InvalidArgs(_))
+    ));
+    assert!(matches!(
+      validate_root_dir("/app"),
+      Err(Error::InvalidArgs(_))
+    ));
+  }
+
+  #[test]
+  fn reproducible_zips_are_byte_identical() {
+    let names = ["src/zip.rs", "src/search.rs", "src/error.rs", "src/args.rs"];
+    let build = || {
+      let files: HashSet<String> = names.iter().map(|n| n.to_string()).collect();
+      create_zip(".", files, None, &ZipOptions::default()).unwrap()
+    };
+    let first = build();
+    assert_eq!(first, build());
+
+    let mut zip = ZipArchive::new(Cursor::new(first)).unwrap();
+    let entries: Vec<String> = (0..zip.len())
+      .map(|ix| zip.by_index(ix).unwrap().name().to_string())
+      .collect();
+    let mut sorted = entries.clone();
+    sorted.sort();
+    assert_eq!(entries.len(), names.len());
+    assert_eq!(entries, sorted);
+    let entry = zip.by_name("src/zip.rs").unwrap();
+    assert_eq!(entry.last_modified().unwrap().year(), 1980);
+    assert_eq!(entry.unix_mode(), Some(0o100644));
+  }
+
+  #[test]
+  fn source_date_epoch_must_be_a_number() {
+    assert!(matches!(
+      reproducible_mtime(Some("yesterday")),
+      Err(Error::InvalidArgs(_))
+    ));
+    assert_eq!(reproducible_mtime(Some("0")).unwrap().year(), 1980);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn preserved_symlinks_are_stored_as_links() {
+    let (_guard, dir) = temp_dir();
+    fs::write(dir.join("main.js"), "main").unwrap();
+    std::os::unix::fs::symlink("main.js", dir.join("entry.js")).unwrap();
+    let files: HashSet<String> = ["main.js", "entry.js"]
+      .iter()
+      .map(|name| dir.join(name).to_str().unwrap().to_string())
+      .collect();
+    let options = ZipOptions {
+      symlinks: SymlinkMode::Preserve,
+      ..Default::default()
+    };
+    let result = create_zip(dir.to_str().unwrap(), files, None, &options).unwrap();
+
+    let mut zip = ZipArchive::new(Cursor::new(result)).unwrap();
+    let mut link = zip.by_name("entry.js").unwrap();
+    assert_eq!(link.unix_mode().unwrap() & 0o170000, 0o120000);
+    let mut target = String::new();
+    link.read_to_string(&mut target).unwrap();
+    assert_eq!(target, "main.js");
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn executable_bits_are_kept() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (_guard, dir) = temp_dir();
+    for (name, mode) in [("bootstrap", 0o644), ("helper", 0o750), ("index.js", 0o600)] {
+      fs::write(dir.join(name), name).unwrap();
+      fs::set_permissions(dir.join(name), fs::Permissions::from_mode(mode)).unwrap();
+    }
+    let files: HashSet<String> = ["bootstrap", "helper", "index.js"]
+      .iter()
+      .map(|name| dir.join(name).to_str().unwrap().to_string())
+      .collect();
+    let path = dir.to_str().unwrap().to_string();
+    let mode_of = |options: &ZipOptions, name: &str| {
+      let result = create_zip(&path, files.clone(), None, options).unwrap();
+      let mut zip = ZipArchive::new(Cursor::new(result)).unwrap();
+      let mode = zip.by_name(name).unwrap().unix_mode().unwrap() & 0o777;
+      mode
+    };
+
+    let reproducible = ZipOptions {
+      executables: parse_patterns(&["bootstrap".to_string()], false).unwrap(),
+      ..Default::default()
+    };
+    assert_eq!(mode_of(&reproducible, "bootstrap"), 0o755);
+    assert_eq!(mode_of(&reproducible, "helper"), 0o755);
+    assert_eq!(mode_of(&reproducible, "index.js"), 0o644);
+
+    let as_on_disk = ZipOptions {
+      reproducible: false,
+      ..Default::default()
+    };
+    assert_eq!(mode_of(&as_on_disk, "bootstrap"), 0o644);
+    assert_eq!(mode_of(&as_on_disk, "helper"), 0o750);
+    assert_eq!(mode_of(&as_on_disk, "index.js"), 0o600);
+  }
+
+  #[test]
+  fn already_compressed_files_are_stored() {
+    let files: HashSet<String> = ["src/zip.rs", "src/lib.rs"]
+      .iter()
+      .map(|name| name.to_string())
+      .collect();
+    let options = ZipOptions {
+      compression: Compression::Zstd,
+      compression_level: Some(3),
+      stored: parse_patterns(&["**/*LIB.rs".to_string()], true).unwrap(),
+      ..Default::default()
+    };
+    let result = create_zip(".", files, None, &options).unwrap();
+
+    let mut zip = ZipArchive::new(Cursor::new(result)).unwrap();
+    let method =
+      |zip: &mut ZipArchive<Cursor<Vec<u8>>>, name| zip.by_name(name).unwrap().compression();
+    assert_eq!(method(&mut zip, "src/zip.rs"), CompressionMethod::Zstd);
+    assert_eq!(method(&mut zip, "src/lib.rs"), CompressionMethod::Stored);
+    let mut contents = String::new();
+    zip
+      .by_name("src/zip.rs")
+      .unwrap()
+      .read_to_string(&mut contents)
+      .unwrap();
+    assert_eq!(contents, fs::read_to_string("src/zip.rs").unwrap());
+
+    // like the include globs, a `*` doesn't reach into directories
+    let top_level_only = parse_patterns(&["*.rs".to_string()], false).unwrap();
+    assert!(!top_level_only.is_match(Path::new("src/lib.rs")));
+    assert!(parse_patterns(&["".to_string()], false)
+      .unwrap()
+      .matched(Path::new(""))
+      .is_none());
+  }
+
+  #[test]
+  fn parallel_builds_match_single_threaded_ones() {
+    let files: HashSet<String> = glob::glob("src/**/*.rs")
+      .unwrap()
+      .map(|path| path.unwrap().to_str().unwrap().to_string())
+      .collect();
+    let build_on = |threads| {
+      let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .unwrap();
+      pool.install(|| create_zip(".", files.clone(), None, &ZipOptions::default()).unwrap())
+    };
+    assert_eq!(build_on(1), build_on(4));
+  }
 }